@@ -1,4 +1,5 @@
 extern crate pretty_env_logger;
+use std::time::Duration;
 use url::Url;
 
 fn main() {
@@ -11,7 +12,8 @@ fn main() {
         let url = Url::parse(&format!("rtmp://{}/vod/media", addr)).expect("url parse");
 
         let mut conn = rtmp::Connection::new(url);
-        // optional set timeout to 1 sec: conn.set_timeout(1000);
+        // optional, give up if the server takes more than a second
+        conn.set_timeout(Duration::from_secs(1));
         let response = conn.connect().await.unwrap();
         println!("===> connect response: {:?}", response);
     });
@@ -15,6 +15,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let response = cn.connect().await?;
     println!("===> connect response: {:?}", response);
+    cn.set_chunk_size(4096).await?;
 
     // let (maybe_stream, message) = match cn.new_stream().await {
     //     Err(err_message) => (None, err_message.0),
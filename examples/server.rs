@@ -0,0 +1,31 @@
+extern crate pretty_env_logger;
+use rtmp::server::{Server, ServerEvent};
+
+// try it with: ffmpeg -re -i video.mp4 -c copy -f flv rtmp://127.0.0.1:1935/live/key
+fn main() {
+    let mut runtime = tokio::runtime::Runtime::new().expect("new Runtime");
+    runtime.block_on(async {
+        pretty_env_logger::init();
+
+        let mut server = Server::bind("127.0.0.1:1935").await.expect("bind");
+        loop {
+            let (mut session, addr) = server.accept().await.expect("accept");
+            println!("===> client {}", addr);
+            tokio::spawn(async move {
+                loop {
+                    match session.next_event().await {
+                        Ok(Some(ServerEvent::Audio { .. }))
+                        | Ok(Some(ServerEvent::Video { .. })) => {}
+                        Ok(Some(event)) => println!("===> {}: {:?}", addr, event),
+                        Ok(None) => break,
+                        Err(err) => {
+                            println!("===> {}: {}", addr, err);
+                            break;
+                        }
+                    }
+                }
+                println!("===> client {} disconnected", addr);
+            });
+        }
+    });
+}
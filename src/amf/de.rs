@@ -0,0 +1,450 @@
+//! Converts AMF0 values to Rust types with serde, the reverse of `ser`.
+//!
+//! Numbers deserialize into any integer type when they have no fractional
+//! part and fit.  `Object`, `EcmaArray` and `TypedObject` all deserialize as
+//! structs or maps.  A `TypedObject` deserializes into an enum by picking
+//! the variant named after its class.
+
+use super::{Error, Value, ValueMap};
+use serde::de::{self, DeserializeOwned, Visitor};
+use serde::forward_to_deserialize_any;
+
+/// Converts an AMF0 value to `T`
+///
+/// ```
+/// use rtmp::amf::{self, Value, ValueMap};
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize, Debug, PartialEq)]
+/// struct Status {
+///     level: String,
+///     code: String,
+/// }
+///
+/// let mut h = ValueMap::new();
+/// h.insert("level".to_string(), "status".into());
+/// h.insert("code".to_string(), "NetStream.Play.Start".into());
+/// let status: Status = amf::from_value(Value::Object(h)).unwrap();
+/// assert_eq!(status.code, "NetStream.Play.Start");
+/// ```
+pub fn from_value<T>(value: Value) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    T::deserialize(value)
+}
+
+/// Converts AMF0 bytes holding exactly one value to `T`
+pub fn from_bytes<T>(bytes: &[u8]) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    let (value, len) = Value::from_bytes(bytes)?;
+    if len < bytes.len() {
+        return Err(Error::TrailingBytes(bytes.len() - len));
+    }
+    from_value(value)
+}
+
+impl Value {
+    fn invalid_type<E: de::Error>(&self, expected: &dyn de::Expected) -> E {
+        let unexpected = match self {
+            Value::Number(n) => de::Unexpected::Float(*n),
+            Value::Boolean(b) => de::Unexpected::Bool(*b),
+            Value::Utf8(s) => de::Unexpected::Str(s),
+            Value::Null | Value::Undefined => de::Unexpected::Unit,
+            Value::StrictArray(_) => de::Unexpected::Seq,
+            Value::Object(_) | Value::EcmaArray(_) | Value::TypedObject { .. } => {
+                de::Unexpected::Map
+            }
+            _ => de::Unexpected::Other("AMF0 value"),
+        };
+        de::Error::invalid_type(unexpected, expected)
+    }
+}
+
+// integers are sent as doubles, so accept any whole number in range.
+// MAX as f64 rounds up to a power of two, hence the strict upper bound.
+macro_rules! deserialize_integer {
+    ($method:ident, $visit:ident, $ty:ty) => {
+        fn $method<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self {
+                Value::Number(n) if n.fract() == 0.0 => {
+                    if n >= <$ty>::MIN as f64 && n < <$ty>::MAX as f64 + 1.0 {
+                        visitor.$visit(n as $ty)
+                    } else {
+                        Err(de::Error::invalid_value(de::Unexpected::Float(n), &visitor))
+                    }
+                }
+                other => other.deserialize_any(visitor),
+            }
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Value::Number(n) => visitor.visit_f64(n),
+            Value::Boolean(b) => visitor.visit_bool(b),
+            Value::Utf8(s) | Value::XmlDocument(s) => visitor.visit_string(s),
+            Value::Null | Value::Undefined | Value::Unsupported => visitor.visit_unit(),
+            Value::StrictArray(values) => visitor.visit_seq(SeqDeserializer {
+                iter: values.into_iter(),
+            }),
+            Value::Date { millis, .. } => visitor.visit_f64(millis),
+            Value::Object(properties)
+            | Value::EcmaArray(properties)
+            | Value::TypedObject { properties, .. } => visitor.visit_map(MapDeserializer {
+                iter: properties.into_iter(),
+                value: None,
+            }),
+            Value::AvmPlus(_) => Err(Error::Message(
+                "AMF3 values can not be deserialized".to_string(),
+            )),
+        }
+    }
+
+    deserialize_integer!(deserialize_i8, visit_i64, i64);
+    deserialize_integer!(deserialize_i16, visit_i64, i64);
+    deserialize_integer!(deserialize_i32, visit_i64, i64);
+    deserialize_integer!(deserialize_i64, visit_i64, i64);
+    deserialize_integer!(deserialize_u8, visit_u64, u64);
+    deserialize_integer!(deserialize_u16, visit_u64, u64);
+    deserialize_integer!(deserialize_u32, visit_u64, u64);
+    deserialize_integer!(deserialize_u64, visit_u64, u64);
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Value::Null | Value::Undefined => visitor.visit_none(),
+            other => visitor.visit_some(other),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Value::Utf8(variant) => visitor.visit_enum(EnumDeserializer {
+                variant,
+                value: None,
+            }),
+            Value::TypedObject {
+                class_name,
+                properties,
+            } => visitor.visit_enum(EnumDeserializer {
+                variant: class_name,
+                value: Some(Value::Object(properties)),
+            }),
+            Value::Object(properties) if properties.len() == 1 => {
+                let (variant, value) = properties.into_iter().next().unwrap();
+                visitor.visit_enum(EnumDeserializer {
+                    variant,
+                    value: Some(value),
+                })
+            }
+            other => Err(other.invalid_type(&"string, typed object or single key object")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i128 u128 f32 f64 char str string bytes byte_buf unit unit_struct
+        seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct SeqDeserializer {
+    iter: std::vec::IntoIter<Value>,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqDeserializer {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Error>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        match self.iter.next() {
+            Some(value) => seed.deserialize(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct MapDeserializer {
+    iter: <ValueMap as IntoIterator>::IntoIter,
+    value: Option<Value>,
+}
+
+impl<'de> de::MapAccess<'de> for MapDeserializer {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Error>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(Value::Utf8(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        match self.value.take() {
+            Some(value) => seed.deserialize(value),
+            None => Err(Error::Message("object value without a key".to_string())),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct EnumDeserializer {
+    variant: String,
+    value: Option<Value>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = VariantDeserializer;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, VariantDeserializer), Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(Value::Utf8(self.variant))?;
+        Ok((variant, VariantDeserializer { value: self.value }))
+    }
+}
+
+struct VariantDeserializer {
+    value: Option<Value>,
+}
+
+impl<'de> de::VariantAccess<'de> for VariantDeserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.value {
+            None | Some(Value::Null) => Ok(()),
+            Some(other) => Err(other.invalid_type(&"unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Error>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        match self.value {
+            Some(value) => seed.deserialize(value),
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"newtype variant",
+            )),
+        }
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Some(value @ Value::StrictArray(_)) => {
+                de::Deserializer::deserialize_any(value, visitor)
+            }
+            Some(other) => Err(other.invalid_type(&"tuple variant")),
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"tuple variant",
+            )),
+        }
+    }
+
+    fn struct_variant<V>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Some(value @ Value::Object(_)) => de::Deserializer::deserialize_any(value, visitor),
+            Some(other) => Err(other.invalid_type(&"struct variant")),
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"struct variant",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::{to_bytes, to_value};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Metadata {
+        width: u32,
+        height: u32,
+        framerate: f64,
+        encoder: Option<String>,
+        stereo: bool,
+    }
+
+    #[test]
+    fn can_round_trip_struct() {
+        let metadata = Metadata {
+            width: 1280,
+            height: 720,
+            framerate: 29.97,
+            encoder: None,
+            stereo: true,
+        };
+        let bytes = to_bytes(&metadata).expect("serialize");
+        let result: Metadata = from_bytes(&bytes).expect("deserialize");
+        assert_eq!(result, metadata);
+    }
+
+    #[test]
+    fn can_deserialize_ecma_array_as_map() {
+        let mut h = ValueMap::new();
+        h.insert("a".to_string(), Value::Number(1.0));
+        h.insert("b".to_string(), Value::Number(2.0));
+        let result: HashMap<String, u8> = from_value(Value::EcmaArray(h)).expect("deserialize");
+        assert_eq!(result.get("a"), Some(&1));
+        assert_eq!(result.get("b"), Some(&2));
+    }
+
+    #[test]
+    fn rejects_fractional_integer() {
+        let result: Result<u32, Error> = from_value(Value::Number(1.5));
+        assert!(result.is_err());
+        let result: Result<u8, Error> = from_value(Value::Number(256.0));
+        assert!(result.is_err());
+        let result: Result<u32, Error> = from_value(Value::Number(-5.0));
+        assert!(result.is_err());
+        let result: Result<u64, Error> = from_value(Value::Number(1e30));
+        assert!(result.is_err());
+        let result: Result<u64, Error> = from_value(Value::Number(18446744073709551616.0));
+        assert!(result.is_err());
+        let result: Result<i64, Error> = from_value(Value::Number(-1e30));
+        assert!(result.is_err());
+        let result: Result<i64, Error> = from_value(Value::Number(-9223372036854775808.0));
+        assert_eq!(result, Ok(i64::MIN));
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Class {
+        #[serde(rename = "geom.Point")]
+        Point(Point),
+        #[serde(rename = "geom.Line")]
+        Line {
+            from: Point,
+            to: Point,
+        },
+        Nothing,
+    }
+
+    #[test]
+    fn can_deserialize_typed_object_by_class_name() {
+        let mut properties = ValueMap::new();
+        properties.insert("x".to_string(), Value::Number(1.0));
+        properties.insert("y".to_string(), Value::Number(2.0));
+        let value = Value::TypedObject {
+            class_name: "geom.Point".to_string(),
+            properties,
+        };
+        let class: Class = from_value(value.clone()).expect("deserialize");
+        assert_eq!(class, Class::Point(Point { x: 1.0, y: 2.0 }));
+        assert_eq!(to_value(&class).expect("serialize"), value);
+
+        let line = Class::Line {
+            from: Point { x: 0.0, y: 0.0 },
+            to: Point { x: 1.0, y: 1.0 },
+        };
+        let value = to_value(&line).expect("serialize");
+        assert_eq!(from_value::<Class>(value).expect("deserialize"), line);
+        let value = to_value(&Class::Nothing).expect("serialize");
+        assert_eq!(
+            from_value::<Class>(value).expect("deserialize"),
+            Class::Nothing
+        );
+    }
+
+    #[test]
+    fn can_deserialize_typed_object_into_plain_struct() {
+        let mut properties = ValueMap::new();
+        properties.insert("x".to_string(), Value::Number(1.0));
+        properties.insert("y".to_string(), Value::Number(2.0));
+        let value = Value::TypedObject {
+            class_name: "geom.Point".to_string(),
+            properties: properties.clone(),
+        };
+        let point: Point = from_value(value).expect("deserialize");
+        assert_eq!(point, Point { x: 1.0, y: 2.0 });
+        // the class name is only kept when wrapped in an enum variant
+        assert_eq!(
+            to_value(&point).expect("serialize"),
+            Value::Object(properties)
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let result: Result<Option<String>, Error> = from_bytes(&[0x05, 0x05]);
+        match result {
+            Err(Error::TrailingBytes(1)) => (),
+            other => panic!("expected TrailingBytes, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::{fmt, io, str};
+
+/// Errors decoding AMF, or converting between AMF0 and Rust types with serde
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Truncated(usize),     // input ended, this many more bytes are needed
+    UnexpectedMarker(u8), // unknown or unsupported type marker
+    InvalidUtf8(str::Utf8Error),
+    BadReference(u32),    // reference to a value not read yet
+    TooLarge,             // references copy too much data
+    TooDeep,              // values nested too deeply
+    TooLong(usize),       // string or count too long for its length field
+    TrailingBytes(usize), // bytes left over after the value
+    Message(String),      // from serde, or a value of the wrong type
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Truncated(n) => write!(f, "AMF value truncated, {} more bytes needed", n),
+            Error::UnexpectedMarker(m) => write!(f, "unexpected AMF marker {:#04x}", m),
+            Error::InvalidUtf8(err) => write!(f, "AMF string is not UTF-8: {}", err),
+            Error::BadReference(index) => write!(f, "AMF reference {} not found", index),
+            Error::TooLarge => write!(f, "AMF references expand to too much data"),
+            Error::TooDeep => write!(f, "AMF values nested too deeply"),
+            Error::TooLong(len) => write!(f, "AMF length {} is too long to encode", len),
+            Error::TrailingBytes(n) => write!(f, "{} bytes left after AMF value", n),
+            Error::Message(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<str::Utf8Error> for Error {
+    fn from(err: str::Utf8Error) -> Self {
+        Self::InvalidUtf8(err)
+    }
+}
+
+// for the async readers and writers
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        let kind = match err {
+            Error::Truncated(_) => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
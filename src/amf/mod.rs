@@ -0,0 +1,1076 @@
+extern crate derive_more;
+use bytes::BufMut;
+use derive_more::From;
+use indexmap::IndexMap;
+use log::{info, trace};
+use std::convert::TryInto;
+use tokio::prelude::*;
+
+extern crate num_traits;
+use num_traits::FromPrimitive;
+
+mod de;
+mod error;
+mod ser;
+pub use de::{from_bytes, from_value};
+pub use error::Error;
+pub use ser::{to_bytes, to_value};
+
+#[derive(Debug, Primitive, PartialEq, Clone, Copy)]
+enum Marker {
+    Number = 0,          // f64: 8 byte IEEE-754 double precision floating point
+    Boolean = 1,         // bool
+    Utf8String = 2,      // String
+    Object = 3,          // ValueMap
+    Movieclip = 4,       // reserved, not supported
+    Null = 5,            //
+    Undefined = 6,       //
+    Reference = 7,       // u16 index of an earlier object
+    EcmaArray = 8,       // u32 count, then properties like Object
+    ObjectEnd = 9,       //
+    StrictArray = 10,    // u32 count, then values
+    Date = 11,           // f64 ms since epoch, then i16 timezone
+    LongUtf8String = 12, // String longer than 65535 bytes
+    Unsupported = 13,    //
+    Recordset = 14,      // reserved, not supported
+    XmlDocument = 15,    // String with u32 length
+    TypedObject = 16,    // class name, then properties like Object
+    AvmPlusObject = 17,  // switch to AMF3 for the next value
+}
+
+use Marker::*;
+
+// references let a few bytes of input stand for a copy of a large value,
+// so decoding fails once the copies add up to more than this many bytes
+pub(crate) const MAX_COPIED: usize = 1 << 20;
+
+// decoding recurses into nested values, so limit how deep they go
+pub(crate) const MAX_DEPTH: usize = 64;
+
+// lengths come from the peer, so reading a stream grows the buffer by at most
+// this much before the bytes have actually arrived
+const READ_STEP: usize = 64 * 1024;
+
+/// Object properties, kept in the order they were read or inserted so the
+/// same value always encodes to the same bytes
+pub type ValueMap = IndexMap<String, Value>;
+
+// TODO: better to use str so this can be Copy
+#[derive(Debug, PartialEq, From, Clone)]
+pub enum Value {
+    Number(f64),
+    Boolean(bool),
+    Utf8(String),
+    Object(ValueMap),
+    Null,
+    #[from(ignore)]
+    EcmaArray(ValueMap), // associative array, written with a count
+    #[from(ignore)]
+    Undefined,
+    StrictArray(Vec<Value>),
+    #[from(ignore)]
+    Date {
+        millis: f64,   // since the epoch, UTC
+        timezone: i16, // reserved, should be 0
+    },
+    #[from(ignore)]
+    Unsupported,
+    #[from(ignore)]
+    XmlDocument(String),
+    #[from(ignore)]
+    TypedObject {
+        class_name: String,
+        properties: ValueMap,
+    },
+    AvmPlus(Box<crate::amf3::Value>), // AMF3 value, after an avmplus-object marker
+}
+
+// Doc for #[derive(From)]
+/// # Examples
+///
+/// ```
+/// use rtmp::amf::Value;
+///
+/// let val: Value = (2.0).into();
+/// assert_eq!(val, Value::Number(2.0));
+///
+/// let val: Value = true.into();
+/// assert_eq!(val, Value::Boolean(true));
+///
+/// let s = "hello".to_string();
+/// let val: Value = s.into();
+/// assert_eq!(val, Value::Utf8("hello".to_string()));
+///
+/// let val: Value = "hello".into();
+/// assert_eq!(val, Value::Utf8("hello".to_string()));
+/// ```
+impl std::convert::From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Utf8(s.to_string())
+    }
+}
+
+impl Value {
+    ///
+    /// ```
+    /// use rtmp::amf::Value;
+    /// let val = Value::Number(1.0);
+    /// let num = val.as_number().unwrap();
+    /// assert_eq!(num, 1.0);
+    /// ```
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+    ///
+    /// ```
+    /// use rtmp::amf::Value;
+    /// let val = Value::Utf8("hello".to_string());
+    /// let s = val.as_string().unwrap();
+    /// assert_eq!(s, "hello".to_string());
+    /// ```
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            Value::Utf8(s) => Some(s.to_owned()),
+            _ => None,
+        }
+    }
+
+    pub fn as_str<'a>(&'a self) -> Option<&'a str> {
+        match self {
+            Value::Utf8(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn object_get_string(h: &ValueMap, label: &str) -> Option<String> {
+        if let Some(value_ref) = h.get(&label.to_string()) {
+            (*value_ref).as_string()
+        } else {
+            None
+        }
+    }
+
+    pub fn object_get_str<'a>(h: &'a ValueMap, label: &str) -> Option<&'a str> {
+        if let Some(value_ref) = h.get(&label.to_string()) {
+            (*value_ref).as_str()
+        } else {
+            None
+        }
+    }
+
+    pub fn object_get_number(h: &ValueMap, label: &str) -> Option<f64> {
+        if let Some(value_ref) = h.get(&label.to_string()) {
+            (*value_ref).as_number()
+        } else {
+            None
+        }
+    }
+
+    /// properties of an Object, EcmaArray or TypedObject
+    pub fn properties(&self) -> Option<&ValueMap> {
+        match self {
+            Value::Object(h) | Value::EcmaArray(h) => Some(h),
+            Value::TypedObject { properties, .. } => Some(properties),
+            _ => None,
+        }
+    }
+
+    ///
+    /// ```
+    /// use rtmp::amf::{Value, ValueMap};
+    /// let mut h = ValueMap::new();
+    /// h.insert("code".to_string(), "NetStream.Play.Start".into());
+    /// let val = Value::Object(h);
+    /// assert_eq!(val.get("code").and_then(Value::as_str), Some("NetStream.Play.Start"));
+    /// assert_eq!(val.get("level"), None);
+    /// ```
+    pub fn get(&self, label: &str) -> Option<&Value> {
+        self.properties().and_then(|h| h.get(label))
+    }
+
+    /// decodes one value from the start of the bytes, returning it with the
+    /// number of bytes used
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Value, usize), Error> {
+        let mut decoder = Decoder::new(bytes);
+        let value = decoder.decode()?;
+        Ok((value, decoder.pos))
+    }
+
+    /// appends the encoded value to the buffer, failing for property
+    /// names and class names over 65535 bytes
+    pub fn encode<B: BufMut>(&self, buf: &mut B) -> Result<(), Error> {
+        encode(buf, self)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        encode(&mut buf, self)?;
+        Ok(buf)
+    }
+
+    pub async fn read<T>(reader: T) -> io::Result<Value>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let value = read_incrementally(reader, |bytes| Decoder::new(bytes).decode()).await?;
+        info!(target: "amf::Value::read", "read value: {:?}", value);
+        Ok(value)
+    }
+
+    pub async fn write_string<T>(mut writer: T, s: String, write_marker: bool) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let mut buf = Vec::new();
+        if write_marker {
+            encode_str(&mut buf, &s);
+        } else {
+            encode_string(&mut buf, &s)?;
+        }
+        writer.write_all(&buf).await
+    }
+
+    pub async fn write_number<T>(mut writer: T, num: f64, write_marker: bool) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        if write_marker {
+            writer.write_u8(Number as u8).await?;
+        }
+        writer.write_all(&f64::to_be_bytes(num)).await
+    }
+
+    pub async fn write<T>(mut writer: T, value: Value) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        trace!(target: "amf::Value::write", "value: {:?}", value);
+        writer.write_all(&value.to_bytes()?).await
+    }
+} // impl Value
+
+// Values carry no overall length, so read just the bytes the decoder says
+// are missing until it can finish.  Each attempt decodes the whole buffer
+// again, so only retry once all of those bytes are in.
+pub(crate) async fn read_incrementally<T, V, F>(mut reader: T, decode: F) -> io::Result<V>
+where
+    T: AsyncRead + Unpin,
+    F: Fn(&[u8]) -> Result<V, Error>,
+{
+    let mut buf = Vec::new();
+    loop {
+        match decode(&buf) {
+            Err(Error::Truncated(mut needed)) => {
+                while needed > 0 {
+                    let start = buf.len();
+                    let step = needed.min(READ_STEP);
+                    buf.resize(start + step, 0);
+                    reader.read_exact(&mut buf[start..]).await?;
+                    needed -= step;
+                }
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Reads AMF0 values from a byte slice.  Objects and arrays are numbered in
+/// the order they start, so Reference markers can refer back to them.
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    references: Vec<Option<Value>>, // None while the value is being read
+    sizes: Vec<usize>,              // of each reference written out in full
+    heights: Vec<usize>,            // levels below each reference
+    copied: usize,                  // bytes of values copied by reference
+    depth: usize,                   // of the value being read
+    deepest: usize,                 // depth reached inside the value being read
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            references: Vec::new(),
+            sizes: Vec::new(),
+            heights: Vec::new(),
+            copied: 0,
+            depth: 0,
+            deepest: 0,
+        }
+    }
+
+    /// number of bytes consumed so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// whether every byte of the input has been decoded
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.input.len()
+    }
+
+    // bytes the values read so far would take without references
+    fn expanded(&self) -> usize {
+        self.pos + self.copied
+    }
+
+    // a reference puts a copy of the value at the current depth
+    fn copy(&mut self, index: usize) -> Result<(), Error> {
+        let deepest = self.depth + self.heights[index];
+        if deepest > MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        self.deepest = self.deepest.max(deepest);
+        self.copied += self.sizes[index];
+        if self.copied > MAX_COPIED {
+            return Err(Error::TooLarge);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let remaining = self.input.len() - self.pos;
+        if remaining < len {
+            return Err(Error::Truncated(len - remaining));
+        }
+        let bytes = &self.input[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_number(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_utf8(&mut self, len: usize) -> Result<String, Error> {
+        let bytes = self.take(len)?;
+        Ok(std::str::from_utf8(bytes)?.to_owned())
+    }
+
+    fn read_string(&mut self) -> Result<String, Error> {
+        let len = self.read_u16()?;
+        self.read_utf8(len.into())
+    }
+
+    fn read_long_string(&mut self) -> Result<String, Error> {
+        let len = self.read_u32()?;
+        self.read_utf8(len as usize)
+    }
+
+    // properties end with an empty name followed by the ObjectEnd marker
+    fn read_properties(&mut self) -> Result<ValueMap, Error> {
+        let mut properties = ValueMap::new();
+        loop {
+            let name = self.read_string()?;
+            if name.is_empty() && self.input.get(self.pos) == Some(&(ObjectEnd as u8)) {
+                self.pos += 1;
+                return Ok(properties);
+            }
+            let value = self.decode()?;
+            properties.insert(name, value);
+        }
+    }
+
+    // containers take their slot in the reference table before their
+    // contents, matching the order of the writer.  start is where the
+    // value began and the deepest level outside it is kept until filled.
+    fn reserve(&mut self, start: usize) -> usize {
+        self.references.push(None);
+        self.sizes.push(start);
+        self.heights.push(self.deepest);
+        self.deepest = self.depth;
+        self.references.len() - 1
+    }
+
+    fn fill(&mut self, index: usize, value: Value) -> Value {
+        self.sizes[index] = self.expanded() - self.sizes[index];
+        let outside = self.heights[index];
+        self.heights[index] = self.deepest - self.depth;
+        self.deepest = self.deepest.max(outside);
+        self.references[index] = Some(value.clone());
+        value
+    }
+
+    /// decodes the next value
+    pub fn decode(&mut self) -> Result<Value, Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        self.depth += 1;
+        self.deepest = self.deepest.max(self.depth);
+        let value = self.decode_value();
+        self.depth -= 1;
+        value
+    }
+
+    fn decode_value(&mut self) -> Result<Value, Error> {
+        let start = self.expanded();
+        let marker_byte = self.read_u8()?;
+        let marker = Marker::from_u8(marker_byte).ok_or(Error::UnexpectedMarker(marker_byte))?;
+        trace!(target: "amf::Value::decode", "marker: {:?}", marker);
+        let value = match marker {
+            Number => Value::Number(self.read_number()?),
+            Boolean => Value::Boolean(self.read_u8()? != 0x00),
+            Utf8String => Value::Utf8(self.read_string()?),
+            Object => {
+                let index = self.reserve(start);
+                let properties = self.read_properties()?;
+                self.fill(index, Value::Object(properties))
+            }
+            Null => Value::Null,
+            Undefined => Value::Undefined,
+            Reference => {
+                // a reference to a value still being read would be circular
+                let index = self.read_u16()?;
+                match self.references.get(index as usize) {
+                    Some(Some(_)) => {}
+                    _ => return Err(Error::BadReference(index.into())),
+                }
+                self.copy(index as usize)?;
+                self.references[index as usize].clone().unwrap()
+            }
+            EcmaArray => {
+                let index = self.reserve(start);
+                // the count is only a hint, properties end like an Object
+                let count = self.read_u32()?;
+                trace!(target: "amf::Value::decode", "ecma array count {}", count);
+                let properties = self.read_properties()?;
+                self.fill(index, Value::EcmaArray(properties))
+            }
+            StrictArray => {
+                let index = self.reserve(start);
+                let count = self.read_u32()?;
+                let mut values = Vec::new();
+                for _ in 0..count {
+                    values.push(self.decode()?);
+                }
+                self.fill(index, Value::StrictArray(values))
+            }
+            Date => {
+                let millis = self.read_number()?;
+                let timezone = self.read_u16()? as i16;
+                Value::Date { millis, timezone }
+            }
+            LongUtf8String => Value::Utf8(self.read_long_string()?),
+            Unsupported => Value::Unsupported,
+            XmlDocument => Value::XmlDocument(self.read_long_string()?),
+            TypedObject => {
+                let index = self.reserve(start);
+                let class_name = self.read_string()?;
+                let properties = self.read_properties()?;
+                self.fill(
+                    index,
+                    Value::TypedObject {
+                        class_name,
+                        properties,
+                    },
+                )
+            }
+            AvmPlusObject => {
+                let mut decoder =
+                    crate::amf3::Decoder::nested(&self.input[self.pos..], self.copied, self.depth);
+                let value = decoder.decode()?;
+                self.pos += decoder.position();
+                self.copied = decoder.copied();
+                self.deepest = self.deepest.max(decoder.deepest());
+                Value::AvmPlus(Box::new(value))
+            }
+            Movieclip | Recordset | ObjectEnd => return Err(Error::UnexpectedMarker(marker_byte)),
+        };
+        Ok(value)
+    }
+}
+
+/// appends a string value, without building a `Value` for it
+pub fn encode_str<B: BufMut>(buf: &mut B, s: &str) {
+    if s.len() > u16::MAX as usize {
+        buf.put_u8(LongUtf8String as u8);
+        buf.put_u32(s.len() as u32);
+        buf.put_slice(s.as_bytes());
+    } else {
+        buf.put_u8(Utf8String as u8);
+        buf.put_u16(s.len() as u16);
+        buf.put_slice(s.as_bytes());
+    }
+}
+
+// string without marker, u16 length, so names can't be any longer
+fn encode_string<B: BufMut>(buf: &mut B, s: &str) -> Result<(), Error> {
+    if s.len() > u16::MAX as usize {
+        return Err(Error::TooLong(s.len()));
+    }
+    buf.put_u16(s.len() as u16);
+    buf.put_slice(s.as_bytes());
+    Ok(())
+}
+
+fn encode_properties<B: BufMut>(buf: &mut B, properties: &ValueMap) -> Result<(), Error> {
+    for (key, value) in properties {
+        encode_string(buf, key)?;
+        encode(buf, value)?;
+    }
+    encode_string(buf, "")?;
+    buf.put_u8(ObjectEnd as u8);
+    Ok(())
+}
+
+fn encode<B: BufMut>(buf: &mut B, value: &Value) -> Result<(), Error> {
+    match value {
+        Value::Number(n) => {
+            buf.put_u8(Number as u8);
+            buf.put_f64(*n);
+        }
+        Value::Boolean(b) => {
+            buf.put_u8(Boolean as u8);
+            buf.put_u8(*b as u8);
+        }
+        Value::Utf8(s) => encode_str(buf, s),
+        Value::Object(properties) => {
+            buf.put_u8(Object as u8);
+            encode_properties(buf, properties)?;
+        }
+        Value::Null => buf.put_u8(Null as u8),
+        Value::EcmaArray(properties) => {
+            buf.put_u8(EcmaArray as u8);
+            buf.put_u32(properties.len() as u32);
+            encode_properties(buf, properties)?;
+        }
+        Value::Undefined => buf.put_u8(Undefined as u8),
+        Value::StrictArray(values) => {
+            buf.put_u8(StrictArray as u8);
+            buf.put_u32(values.len() as u32);
+            for v in values {
+                encode(buf, v)?;
+            }
+        }
+        Value::Date { millis, timezone } => {
+            buf.put_u8(Date as u8);
+            buf.put_f64(*millis);
+            buf.put_i16(*timezone);
+        }
+        Value::Unsupported => buf.put_u8(Unsupported as u8),
+        Value::XmlDocument(s) => {
+            buf.put_u8(XmlDocument as u8);
+            buf.put_u32(s.len() as u32);
+            buf.put_slice(s.as_bytes());
+        }
+        Value::TypedObject {
+            class_name,
+            properties,
+        } => {
+            buf.put_u8(TypedObject as u8);
+            encode_string(buf, class_name)?;
+            encode_properties(buf, properties)?;
+        }
+        Value::AvmPlus(v) => {
+            buf.put_u8(AvmPlusObject as u8);
+            buf.put_slice(&v.to_bytes()?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*; // importing names from outer (for mod tests) scope.
+    use crate::util::bytes_from_hex_string;
+
+    #[tokio::test]
+    async fn can_read_null() {
+        let bytes = bytes_from_hex_string("05");
+        let buf: &[u8] = &bytes;
+        let value = Value::read(buf).await.expect("read");
+        assert_eq!(value, Value::Null)
+    }
+
+    #[tokio::test]
+    async fn cannot_read_past_end_of_long_string() {
+        // claims 4 GiB of text but carries 3 bytes
+        let bytes = bytes_from_hex_string("0c ff ff ff ff 61 62 63");
+        let buf: &[u8] = &bytes;
+        let err = Value::read(buf).await.expect_err("read");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn reads_long_string_before_decoding_again() {
+        let text = "a".repeat(4 * READ_STEP);
+        let mut bytes = vec![LongUtf8String as u8];
+        bytes.extend_from_slice(&(text.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(text.as_bytes());
+        let buf: &[u8] = &bytes;
+        let attempts = std::cell::Cell::new(0);
+        let value = read_incrementally(buf, |bytes| {
+            attempts.set(attempts.get() + 1);
+            Decoder::new(bytes).decode()
+        })
+        .await
+        .expect("read");
+        assert_eq!(value, Value::Utf8(text));
+        // empty, then the marker, the length and the whole value
+        assert_eq!(attempts.get(), 4);
+    }
+
+    #[tokio::test]
+    async fn can_write_null() {
+        let expected = bytes_from_hex_string("05");
+        let mut buf = Vec::new();
+        Value::write(&mut buf, Value::Null).await.expect("write");
+        assert_eq!(buf, expected);
+    }
+
+    // 02                       String type marker
+    // 00 07                    7 bytes long
+    // 63 6f 6e 6e 65 63 74     "connect"
+    #[tokio::test]
+    async fn can_read_string() {
+        let bytes = bytes_from_hex_string("02 00 07 63 6f 6e 6e 65 63 74");
+        let buf: &[u8] = &bytes;
+        let value = Value::read(buf).await.expect("read");
+        match value {
+            Value::Utf8(s) => assert_eq!(s, "connect"),
+            _ => panic!("expected Utf8, got {:?}", value),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_write_string() {
+        let bytes = bytes_from_hex_string("02 00 07 63 6f 6e 6e 65 63 74");
+        let expected: &[u8] = &bytes;
+
+        let mut buf = Vec::new();
+        Value::write(&mut buf, Value::Utf8("connect".to_string()))
+            .await
+            .expect("write");
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn can_read_number_zero() {
+        let num: f64 = 0.0;
+        let byte_array: [u8; 9] = [0x00; 9];
+        let buf: &[u8] = &byte_array;
+        let value = Value::read(buf).await.expect("read");
+        match value {
+            Value::Number(val) => assert_eq!(val, num),
+            _ => panic!("expected Number, got {:?}", value),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_write_number_zero() {
+        let expected: [u8; 9] = [0x00; 9];
+        let mut buf = Vec::new();
+        Value::write(&mut buf, Value::Number(0.0))
+            .await
+            .expect("write");
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn can_read_bool_false() {
+        let bytes = bytes_from_hex_string("01 00");
+        let buf: &[u8] = &bytes;
+        let value = Value::read(buf).await.expect("read");
+        match value {
+            Value::Boolean(s) => assert_eq!(s, false),
+            _ => panic!("expected Boolean, got {:?}", value),
+        }
+    }
+    #[tokio::test]
+    async fn can_read_bool_true() {
+        let bytes = bytes_from_hex_string("01 01");
+        let buf: &[u8] = &bytes;
+        let value = Value::read(buf).await.expect("read");
+        match value {
+            Value::Boolean(s) => assert_eq!(s, true),
+            _ => panic!("expected Boolean, got {:?}", value),
+        }
+    }
+    #[tokio::test]
+    async fn can_write_bool_true() {
+        let expected = bytes_from_hex_string("01 01");
+        let mut buf = Vec::new();
+        Value::write(&mut buf, Value::Boolean(true))
+            .await
+            .expect("write");
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn can_read_object_simple() {
+        // 03                              Object marker
+        //    00 06 66 6d  73 56 65 72                                 label: "fmsVer"
+        //    02 00 0f 46 4d 53 2f 35 2c 30 2c 31  35 2c 35 30 30 34   value: Utf8(""FMS/5,0,15,5004"")
+        //    00 0c 63 61 70 61 62 69 6c 69 74 69 65 73                label: "capabilities"
+        //    00 40 6f e0 00 00 00 00 00                               value: Number(255.0)
+        //    00 04 6d 6f 64 65                                        label: "mode"
+        //    00 3f f0 00 00 00 00 00 00                               value: Number(1.0)
+        //    00 00 09                     ObjectEnd
+
+        let bytes = bytes_from_hex_string(
+            "
+                    03
+                    00 06 66 6d 73 56 65 72
+                    02 00 0f 46 4d 53 2f 35 2c 30 2c 31  35 2c 35 30 30 34
+                    00 0c 63 61 70 61 62 69 6c 69  74 69 65 73
+                    00 40 6f e0 00 00 00 00 00
+                    00 04 6d 6f 64 65
+                    00 3f f0 00 00 00 00 00 00
+                    00 00
+                    09",
+        );
+
+        let buf: &[u8] = &bytes;
+        let value = Value::read(buf).await.expect("read");
+
+        let mut expected = ValueMap::new();
+        expected.insert("capabilities".to_string(), Value::Number(255.0));
+        expected.insert(
+            "fmsVer".to_string(),
+            Value::Utf8("FMS/5,0,15,5004".to_string()),
+        );
+        expected.insert("mode".to_string(), Value::Number(1.0));
+
+        match value {
+            Value::Object(h) => assert_eq!(h, expected),
+            _ => panic!("expected Object, got {:?}", value),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_write_object_simple() {
+        // 03                              Object marker
+        //    00 0c 63 61 70 61 62 69 6c 69 74 69 65 73                label: "capabilities"
+        //    00 40 6f e0 00 00 00 00 00                               value: Number(255.0)
+        //    00 06 66 6d  73 56 65 72                                 label: "fmsVer"
+        //    02 00 0f 46 4d 53 2f 35 2c 30 2c 31  35 2c 35 30 30 34   value: Utf8(""FMS/5,0,15,5004"")
+        //    00 04 6d 6f 64 65                                        label: "mode"
+        //    00 3f f0 00 00 00 00 00 00                               value: Number(1.0)
+        //    00 00 09                     ObjectEnd
+
+        let expected = bytes_from_hex_string(
+            "
+                        03
+                        00 0c 63 61 70 61 62 69 6c 69  74 69 65 73
+                        00 40 6f e0 00 00 00 00 00
+                        00 06 66 6d 73 56 65 72
+                        02 00 0f 46 4d 53 2f 35 2c 30 2c 31  35 2c 35 30 30 34
+                        00 04 6d 6f 64 65
+                        00 3f f0 00 00 00 00 00 00
+                        00 00
+                        09",
+        );
+
+        let mut h = ValueMap::new();
+        h.insert("capabilities".to_string(), Value::Number(255.0));
+        h.insert(
+            "fmsVer".to_string(),
+            Value::Utf8("FMS/5,0,15,5004".to_string()),
+        );
+        h.insert("mode".to_string(), Value::Number(1.0));
+
+        let mut buf = Vec::new();
+        Value::write(&mut buf, Value::Object(h))
+            .await
+            .expect("write");
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_avmplus_value() {
+        // 11                       AvmPlusObject marker
+        // 06 0b 68 65 6c 6c 6f     AMF3 String "hello"
+        // 05                       AMF0 Null follows
+        let bytes = bytes_from_hex_string("11 06 0b 68 65 6c 6c 6f 05");
+        let mut buf: &[u8] = &bytes;
+        let value = Value::read(&mut buf).await.expect("read");
+        let expected = Value::AvmPlus(Box::new(crate::amf3::Value::String("hello".to_string())));
+        assert_eq!(value, expected);
+        assert_eq!(Value::read(&mut buf).await.expect("read"), Value::Null);
+
+        let mut out = Vec::new();
+        Value::write(&mut out, expected).await.expect("write");
+        assert_eq!(out, bytes[..8]);
+    }
+
+    async fn assert_round_trip(hex: &str, value: Value) {
+        let bytes = bytes_from_hex_string(hex);
+        let mut buf: &[u8] = &bytes;
+        assert_eq!(Value::read(&mut buf).await.expect("read"), value);
+        let mut out = Vec::new();
+        Value::write(&mut out, value).await.expect("write");
+        assert_eq!(out, bytes);
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_undefined_and_unsupported() {
+        assert_round_trip("06", Value::Undefined).await;
+        assert_round_trip("0d", Value::Unsupported).await;
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_strict_array() {
+        // 0a 00 00 00 02     StrictArray, 2 values
+        // 00 3f f0 ...       Number 1
+        // 02 00 01 61        String "a"
+        assert_round_trip(
+            "0a 00 00 00 02  00 3f f0 00 00 00 00 00 00  02 00 01 61",
+            Value::StrictArray(vec![Value::Number(1.0), Value::Utf8("a".to_string())]),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_date() {
+        assert_round_trip(
+            "0b 42 77 48 76 e8 00 00 00 00 00",
+            Value::Date {
+                millis: 1.6e12,
+                timezone: 0,
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_long_string() {
+        let s = "x".repeat(0x10000);
+        let mut hex = "0c 00 01 00 00".to_string();
+        hex.push_str(&" 78".repeat(0x10000));
+        assert_round_trip(&hex, Value::Utf8(s)).await;
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_xml_document() {
+        assert_round_trip(
+            "0f 00 00 00 03 3c 61 3e",
+            Value::XmlDocument("<a>".to_string()),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_typed_object() {
+        // 10 00 03 46 6f 6f        TypedObject "Foo"
+        // 00 01 78 00 ...          x: 2
+        // 00 00 09                 end
+        let mut properties = ValueMap::new();
+        properties.insert("x".to_string(), Value::Number(2.0));
+        assert_round_trip(
+            "10 00 03 46 6f 6f  00 01 78 00 40 00 00 00 00 00 00 00  00 00 09",
+            Value::TypedObject {
+                class_name: "Foo".to_string(),
+                properties,
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn can_read_nested_object() {
+        // 03                   Object
+        // 00 01 61 03          a: Object
+        // 00 01 62 05          b: Null
+        // 00 00 09             end of a
+        // 00 00 09             end
+        let bytes = bytes_from_hex_string("03 00 01 61 03 00 01 62 05 00 00 09 00 00 09");
+        let mut inner = ValueMap::new();
+        inner.insert("b".to_string(), Value::Null);
+        let mut outer = ValueMap::new();
+        outer.insert("a".to_string(), Value::Object(inner));
+        let (value, len) = Value::from_bytes(&bytes).expect("decode");
+        assert_eq!(value, Value::Object(outer));
+        assert_eq!(len, bytes.len());
+    }
+
+    #[tokio::test]
+    async fn can_resolve_reference_to_earlier_object() {
+        // 0a 00 00 00 02           StrictArray (reference 0), 2 values
+        // 03 00 01 62 05 00 00 09  Object { b: null } (reference 1)
+        // 07 00 01                 Reference 1
+        let bytes = bytes_from_hex_string("0a 00 00 00 02  03 00 01 62 05 00 00 09  07 00 01");
+        let mut buf: &[u8] = &bytes;
+        let mut h = ValueMap::new();
+        h.insert("b".to_string(), Value::Null);
+        let object = Value::Object(h);
+        assert_eq!(
+            Value::read(&mut buf).await.expect("read"),
+            Value::StrictArray(vec![object.clone(), object])
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_reference_not_yet_seen() {
+        let bytes = bytes_from_hex_string("07 00 00");
+        let err = Value::from_bytes(&bytes).expect_err("decode");
+        assert_eq!(err, Error::BadReference(0));
+    }
+
+    // an array of objects, each holding the previous one twice
+    #[test]
+    fn reference_bomb_is_too_large() {
+        let mut bytes = bytes_from_hex_string("0a 00 00 00 29  03 00 01 61 05 00 00 09");
+        for index in 1..41 {
+            bytes.extend(&[0x03, 0x00, 0x01, 0x61, 0x07, 0x00, index]);
+            bytes.extend(&[0x00, 0x01, 0x62, 0x07, 0x00, index, 0x00, 0x00, 0x09]);
+        }
+        let err = Value::from_bytes(&bytes).expect_err("decode");
+        assert_eq!(err, Error::TooLarge);
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let bytes = bytes_from_hex_string(&"0a 00 00 00 01 ".repeat(5000));
+        let err = Value::from_bytes(&bytes).expect_err("decode");
+        assert_eq!(err, Error::TooDeep);
+
+        // AMF3 values inside count towards the depth
+        let mut bytes = bytes_from_hex_string(&"0a 00 00 00 01 ".repeat(60));
+        bytes.push(0x11);
+        bytes.extend(bytes_from_hex_string(&"09 03 01 ".repeat(10)));
+        let err = Value::from_bytes(&bytes).expect_err("decode");
+        assert_eq!(err, Error::TooDeep);
+    }
+
+    // the copy nests deeper than anything read directly
+    #[test]
+    fn rejects_references_nested_too_deeply() {
+        // [[[...null]]], [[[...<the first>]]]
+        let mut bytes = bytes_from_hex_string("0a 00 00 00 02");
+        bytes.extend(bytes_from_hex_string(&"0a 00 00 00 01 ".repeat(40)));
+        bytes.push(0x05);
+        bytes.extend(bytes_from_hex_string(&"0a 00 00 00 01 ".repeat(39)));
+        bytes.extend(&[0x07, 0x00, 0x01]);
+        let err = Value::from_bytes(&bytes).expect_err("decode");
+        assert_eq!(err, Error::TooDeep);
+    }
+
+    #[tokio::test]
+    async fn rejects_reserved_marker() {
+        let bytes = bytes_from_hex_string("04");
+        let err = Value::from_bytes(&bytes).expect_err("decode");
+        assert_eq!(err, Error::UnexpectedMarker(4));
+    }
+
+    #[test]
+    fn reports_truncated_input_and_bad_utf8() {
+        // String "hello" missing its last 2 bytes
+        let bytes = bytes_from_hex_string("02 00 05 68 65 6c");
+        assert_eq!(Value::from_bytes(&bytes), Err(Error::Truncated(2)));
+        let bytes = bytes_from_hex_string("02 00 02 ff fe");
+        assert!(matches!(
+            Value::from_bytes(&bytes),
+            Err(Error::InvalidUtf8(_))
+        ));
+        // AMF3 errors inside an avmplus value are reported as they are
+        let bytes = bytes_from_hex_string("11 06 0b 68");
+        assert_eq!(Value::from_bytes(&bytes), Err(Error::Truncated(4)));
+    }
+
+    #[test]
+    fn can_decode_values_in_sequence_and_encode_to_buf_mut() {
+        let bytes = bytes_from_hex_string("05 01 01 06");
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.decode(), Ok(Value::Null));
+        assert_eq!(decoder.decode(), Ok(Value::Boolean(true)));
+        assert_eq!(decoder.position(), 3);
+        assert_eq!(decoder.decode(), Ok(Value::Undefined));
+        assert!(decoder.is_empty());
+
+        let mut buf = bytes::BytesMut::new();
+        for value in &[Value::Null, Value::Boolean(true), Value::Undefined] {
+            value.encode(&mut buf).expect("encode");
+        }
+        assert_eq!(&buf[..], &bytes[..]);
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_ecma_array() {
+        // 08 00 00 00 01           EcmaArray, 1 property
+        // 00 01 61 01 01           a: true
+        // 00 00 09                 end
+        let mut h = ValueMap::new();
+        h.insert("a".to_string(), Value::Boolean(true));
+        assert_round_trip(
+            "08 00 00 00 01  00 01 61 01 01  00 00 09",
+            Value::EcmaArray(h),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn can_write_nested_values() {
+        let mut inner = ValueMap::new();
+        inner.insert("b".to_string(), Value::Null);
+        inner.insert(
+            "c".to_string(),
+            Value::StrictArray(vec![Value::Boolean(false)]),
+        );
+        let mut outer = ValueMap::new();
+        outer.insert("a".to_string(), Value::Object(inner));
+        outer.insert("d".to_string(), Value::EcmaArray(ValueMap::new()));
+        // 03                               Object
+        // 00 01 61 03                      a: Object
+        // 00 01 62 05                      b: null
+        // 00 01 63 0a 00 00 00 01 01 00    c: [false]
+        // 00 00 09                         end of a
+        // 00 01 64 08 00 00 00 00 00 00 09 d: empty EcmaArray
+        // 00 00 09                         end
+        assert_round_trip(
+            "03  00 01 61 03  00 01 62 05  00 01 63 0a 00 00 00 01 01 00  00 00 09
+             00 01 64 08 00 00 00 00 00 00 09  00 00 09",
+            Value::Object(outer),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn keeps_property_order() {
+        // 03  00 01 7a 05  00 01 61 05  00 00 09     { z: null, a: null }
+        let bytes = bytes_from_hex_string("03 00 01 7a 05 00 01 61 05 00 00 09");
+        let (value, _) = Value::from_bytes(&bytes).expect("decode");
+        let keys: Vec<&String> = value.properties().expect("object").keys().collect();
+        assert_eq!(keys, vec!["z", "a"]);
+        assert_eq!(value.get("a"), Some(&Value::Null));
+        assert_eq!(value.to_bytes(), Ok(bytes));
+    }
+
+    #[test]
+    fn rejects_names_too_long_to_encode() {
+        let name = "n".repeat(65536);
+        let mut properties = ValueMap::new();
+        properties.insert(name.clone(), Value::Null);
+        let value = Value::Object(properties.clone());
+        assert_eq!(value.to_bytes(), Err(Error::TooLong(65536)));
+
+        let value = Value::TypedObject {
+            class_name: name,
+            properties: ValueMap::new(),
+        };
+        assert_eq!(value.to_bytes(), Err(Error::TooLong(65536)));
+
+        // values may be longer, they're sent as LongUtf8String
+        properties.clear();
+        properties.insert("n".repeat(65535), Value::Utf8("v".repeat(65536)));
+        assert!(Value::Object(properties).to_bytes().is_ok());
+    }
+} // mod tests
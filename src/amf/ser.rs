@@ -0,0 +1,474 @@
+//! Converts Rust types to AMF0 values with serde.
+//!
+//! Numbers of every width become `Number`, sequences and tuples become
+//! `StrictArray`, and structs and maps become anonymous `Object`s.  Enum
+//! variants holding a struct become a `TypedObject` named after the variant,
+//! so `#[serde(rename = "...")]` on the variant sets the class name.  This is
+//! the way to send a typed object: a plain struct's name is that of the Rust
+//! type, not a class the peer knows, so it is dropped.  Other variants follow
+//! serde's usual external tagging: unit variants are strings, the rest an
+//! object with the variant name as its only key.
+
+use super::{Error, Value, ValueMap};
+use serde::ser::{self, Serialize};
+
+/// Converts `value` to an AMF0 value
+///
+/// ```
+/// use rtmp::amf::{self, Value, ValueMap};
+/// use serde::Serialize;
+///
+/// #[derive(Serialize)]
+/// struct Connect<'a> {
+///     app: &'a str,
+///     capabilities: u32,
+/// }
+///
+/// let value = amf::to_value(&Connect { app: "live", capabilities: 15 }).unwrap();
+/// let mut expected = ValueMap::new();
+/// expected.insert("app".to_string(), "live".into());
+/// expected.insert("capabilities".to_string(), 15.0.into());
+/// assert_eq!(value, Value::Object(expected));
+/// ```
+pub fn to_value<T>(value: &T) -> Result<Value, Error>
+where
+    T: Serialize + ?Sized,
+{
+    value.serialize(Serializer)
+}
+
+/// Converts `value` to AMF0 bytes
+pub fn to_bytes<T>(value: &T) -> Result<Vec<u8>, Error>
+where
+    T: Serialize + ?Sized,
+{
+    to_value(value)?.to_bytes()
+}
+
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeTupleVariant;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeStructVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    // AMF0 only has doubles, so 64 bit integers beyond 2^53 lose precision
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(Value::Number(v as f64))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        Ok(Value::Number(v as f64))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(Value::Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::Utf8(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::Utf8(v.to_string()))
+    }
+
+    // AMF0 has no byte array, so bytes are an array of numbers
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        let values = v.iter().map(|b| Value::Number((*b).into())).collect();
+        Ok(Value::StrictArray(values))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Value, Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(Value::Utf8(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Value, Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        match value.serialize(Serializer)? {
+            Value::Object(properties) => Ok(Value::TypedObject {
+                class_name: variant.to_string(),
+                properties,
+            }),
+            other => {
+                let mut h = ValueMap::new();
+                h.insert(variant.to_string(), other);
+                Ok(Value::Object(h))
+            }
+        }
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeVec, Error> {
+        Ok(SerializeVec {
+            values: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeVec, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeVec, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeTupleVariant, Error> {
+        Ok(SerializeTupleVariant {
+            variant,
+            values: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap, Error> {
+        Ok(SerializeMap {
+            properties: ValueMap::new(),
+            next_key: None,
+        })
+    }
+
+    // the Rust type name isn't an AMF class name, see the module docs
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeStructVariant, Error> {
+        Ok(SerializeStructVariant {
+            variant,
+            properties: ValueMap::new(),
+        })
+    }
+}
+
+struct SerializeVec {
+    values: Vec<Value>,
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.values.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::StrictArray(self.values))
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct SerializeTupleVariant {
+    variant: &'static str,
+    values: Vec<Value>,
+}
+
+impl ser::SerializeTupleVariant for SerializeTupleVariant {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.values.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        let mut h = ValueMap::new();
+        h.insert(self.variant.to_string(), Value::StrictArray(self.values));
+        Ok(Value::Object(h))
+    }
+}
+
+struct SerializeMap {
+    properties: ValueMap,
+    next_key: Option<String>,
+}
+
+// property names are strings, but numeric keys are allowed like in JSON
+fn key_to_string(key: Value) -> Result<String, Error> {
+    match key {
+        Value::Utf8(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        other => Err(Error::Message(format!(
+            "object key must be a string, found {:?}",
+            other
+        ))),
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.next_key = Some(key_to_string(to_value(key)?)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| Error::Message("object value without a key".to_string()))?;
+        self.properties.insert(key, to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::Object(self.properties))
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.properties.insert(key.to_string(), to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::Object(self.properties))
+    }
+}
+
+struct SerializeStructVariant {
+    variant: &'static str,
+    properties: ValueMap,
+}
+
+impl ser::SerializeStructVariant for SerializeStructVariant {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.properties.insert(key.to_string(), to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::TypedObject {
+            class_name: self.variant.to_string(),
+            properties: self.properties,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Status {
+        level: String,
+        code: String,
+        description: Option<String>,
+    }
+
+    #[test]
+    fn can_serialize_struct_in_field_order() {
+        let status = Status {
+            level: "status".to_string(),
+            code: "NetStream.Play.Start".to_string(),
+            description: None,
+        };
+        let value = to_value(&status).expect("serialize");
+        let keys: Vec<&String> = value.properties().expect("object").keys().collect();
+        assert_eq!(keys, vec!["level", "code", "description"]);
+        assert_eq!(value.get("description"), Some(&Value::Null));
+    }
+
+    #[test]
+    fn can_serialize_sequences_and_numbers() {
+        let value = to_value(&(1u8, -2i64, vec![0.5f32])).expect("serialize");
+        assert_eq!(
+            value,
+            Value::StrictArray(vec![
+                Value::Number(1.0),
+                Value::Number(-2.0),
+                Value::StrictArray(vec![Value::Number(0.5)]),
+            ])
+        );
+    }
+
+    #[derive(Serialize)]
+    enum Shape {
+        #[serde(rename = "geom.Point")]
+        Point {
+            x: f64,
+            y: f64,
+        },
+        Empty,
+    }
+
+    #[test]
+    fn can_serialize_struct_variant_as_typed_object() {
+        let value = to_value(&Shape::Point { x: 1.0, y: 2.0 }).expect("serialize");
+        let mut properties = ValueMap::new();
+        properties.insert("x".to_string(), Value::Number(1.0));
+        properties.insert("y".to_string(), Value::Number(2.0));
+        assert_eq!(
+            value,
+            Value::TypedObject {
+                class_name: "geom.Point".to_string(),
+                properties
+            }
+        );
+        assert_eq!(
+            to_value(&Shape::Empty).expect("serialize"),
+            Value::Utf8("Empty".to_string())
+        );
+    }
+
+    #[test]
+    fn can_serialize_to_bytes() {
+        let bytes = to_bytes(&Some("a")).expect("serialize");
+        assert_eq!(bytes, vec![0x02, 0x00, 0x01, 0x61]);
+        assert_eq!(to_bytes(&None::<String>).expect("serialize"), vec![0x05]);
+    }
+}
@@ -0,0 +1,965 @@
+// AMF3 is the ActionScript 3 object encoding. In RTMP it appears inside
+// AMF0 values after an avmplus-object marker (0x11), mostly in messages
+// sent by clients that connected with objectEncoding 3.
+use crate::amf::{Error, MAX_COPIED, MAX_DEPTH};
+use log::trace;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use tokio::prelude::*;
+
+extern crate num_traits;
+use num_traits::FromPrimitive;
+
+#[derive(Debug, Primitive, PartialEq, Clone, Copy)]
+enum Marker {
+    Undefined = 0,
+    Null = 1,
+    False = 2,
+    True = 3,
+    Integer = 4, // U29, signed 29 bits
+    Double = 5,
+    String = 6,
+    XmlDocument = 7,
+    Date = 8,
+    Array = 9,
+    Object = 10,
+    Xml = 11,
+    ByteArray = 12,
+    VectorInt = 13,
+    VectorUint = 14,
+    VectorDouble = 15,
+    VectorObject = 16,
+    Dictionary = 17,
+}
+
+/// smallest and largest integers that fit in a U29, others are sent as Double
+pub const INTEGER_MIN: i32 = -(1 << 28);
+pub const INTEGER_MAX: i32 = (1 << 28) - 1;
+
+// externalizable classes whose data is a single AMF3 value
+const EXTERNALIZABLE_CLASSES: [&str; 3] = [
+    "flex.messaging.io.ArrayCollection",
+    "flex.messaging.io.ArrayList",
+    "flex.messaging.io.ObjectProxy",
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Undefined,
+    Null,
+    Boolean(bool),
+    Integer(i32),
+    Double(f64),
+    String(String),
+    XmlDocument(String),
+    Date(f64), // milliseconds since the epoch, UTC
+    Array {
+        assoc: Vec<(String, Value)>,
+        dense: Vec<Value>,
+    },
+    Object(Object),
+    Xml(String),
+    ByteArray(Vec<u8>),
+    VectorInt {
+        fixed: bool,
+        items: Vec<i32>,
+    },
+    VectorUint {
+        fixed: bool,
+        items: Vec<u32>,
+    },
+    VectorDouble {
+        fixed: bool,
+        items: Vec<f64>,
+    },
+    VectorObject {
+        fixed: bool,
+        type_name: String, // empty for Vector.<*>
+        items: Vec<Value>,
+    },
+    Dictionary {
+        weak_keys: bool,
+        entries: Vec<(Value, Value)>,
+    },
+}
+
+/// Class description shared by objects of the same type.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Traits {
+    pub class_name: String, // empty for anonymous objects
+    pub sealed: Vec<String>,
+    pub dynamic: bool,
+    pub externalizable: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Object {
+    pub traits: Traits,
+    pub sealed: Vec<Value>, // values for traits.sealed, in order
+    pub dynamic: Vec<(String, Value)>,
+    pub external: Option<Box<Value>>, // data of an externalizable object
+}
+
+/// Reads AMF3 values from a byte slice, keeping the string, object and
+/// traits reference tables shared by the values read.
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    strings: Vec<String>,
+    objects: Vec<Option<Value>>, // None while the value is being read
+    sizes: Vec<usize>,           // of each object written out in full
+    heights: Vec<usize>,         // levels below each object
+    traits: Vec<Traits>,
+    copied: usize,  // bytes of values copied by reference
+    depth: usize,   // of the value being read
+    deepest: usize, // depth reached inside the value being read
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            strings: Vec::new(),
+            objects: Vec::new(),
+            sizes: Vec::new(),
+            heights: Vec::new(),
+            traits: Vec::new(),
+            copied: 0,
+            depth: 0,
+            deepest: 0,
+        }
+    }
+
+    // for AMF3 inside AMF0, carrying on from how much its references have
+    // copied and the depth of the value holding this one
+    pub(crate) fn nested(input: &'a [u8], copied: usize, depth: usize) -> Self {
+        Self {
+            copied,
+            depth,
+            deepest: depth,
+            ..Self::new(input)
+        }
+    }
+
+    /// number of bytes consumed so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub(crate) fn copied(&self) -> usize {
+        self.copied
+    }
+
+    pub(crate) fn deepest(&self) -> usize {
+        self.deepest
+    }
+
+    // bytes the values read so far would take without references
+    fn expanded(&self) -> usize {
+        self.pos + self.copied
+    }
+
+    fn copy(&mut self, size: usize) -> Result<(), Error> {
+        self.copied += size;
+        if self.copied > MAX_COPIED {
+            return Err(Error::TooLarge);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let remaining = self.input.len() - self.pos;
+        if remaining < len {
+            return Err(Error::Truncated(len - remaining));
+        }
+        let bytes = &self.input[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_f64(&mut self) -> Result<f64, Error> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    // 7 bits in each of the first 3 bytes while the high bit is set,
+    // then all 8 bits of a 4th byte
+    fn read_u29(&mut self) -> Result<u32, Error> {
+        let mut value: u32 = 0;
+        for _ in 0..3 {
+            let byte = self.read_u8()?;
+            if byte & 0x80 == 0 {
+                return Ok((value << 7) | u32::from(byte));
+            }
+            value = (value << 7) | u32::from(byte & 0x7f);
+        }
+        let byte = self.read_u8()?;
+        Ok((value << 8) | u32::from(byte))
+    }
+
+    fn read_utf8(&mut self, len: usize) -> Result<String, Error> {
+        let bytes = self.take(len)?;
+        Ok(std::str::from_utf8(bytes)?.to_owned())
+    }
+
+    fn read_string(&mut self) -> Result<String, Error> {
+        let header = self.read_u29()?;
+        let index = header >> 1;
+        if header & 1 == 0 {
+            let len = self
+                .strings
+                .get(index as usize)
+                .ok_or(Error::BadReference(index))?
+                .len();
+            self.copy(len)?;
+            return Ok(self.strings[index as usize].clone());
+        }
+        let s = self.read_utf8(index as usize)?;
+        // the empty string is never sent by reference
+        if !s.is_empty() {
+            self.strings.push(s.clone());
+        }
+        Ok(s)
+    }
+
+    // values in the object table start with a U29 that is either a
+    // reference, returned as Err(value), or Ok(header >> 1) for inline data
+    fn read_object_header(&mut self) -> Result<Result<u32, Value>, Error> {
+        let header = self.read_u29()?;
+        if header & 1 == 1 {
+            return Ok(Ok(header >> 1));
+        }
+        // a reference to a value still being read would be circular
+        let index = header >> 1;
+        match self.objects.get(index as usize) {
+            Some(Some(_)) => {
+                // the copy goes at the current depth
+                let deepest = self.depth + self.heights[index as usize];
+                if deepest > MAX_DEPTH {
+                    return Err(Error::TooDeep);
+                }
+                self.deepest = self.deepest.max(deepest);
+                self.copy(self.sizes[index as usize])?;
+                Ok(Err(self.objects[index as usize].clone().unwrap()))
+            }
+            _ => Err(Error::BadReference(index)),
+        }
+    }
+
+    // containers take their slot in the object table before their
+    // contents, so references inside are numbered as the writer did.
+    // start is where the value began and the deepest level outside it is
+    // kept until filled.
+    fn reserve(&mut self, start: usize) -> usize {
+        self.objects.push(None);
+        self.sizes.push(start);
+        self.heights.push(self.deepest);
+        self.deepest = self.depth;
+        self.objects.len() - 1
+    }
+
+    fn fill(&mut self, index: usize, value: Value) -> Value {
+        self.sizes[index] = self.expanded() - self.sizes[index];
+        let outside = self.heights[index];
+        self.heights[index] = self.deepest - self.depth;
+        self.deepest = self.deepest.max(outside);
+        self.objects[index] = Some(value.clone());
+        value
+    }
+
+    // values with nothing nested in them
+    fn register(&mut self, start: usize, value: Value) -> Value {
+        self.sizes.push(self.expanded() - start);
+        self.heights.push(0);
+        self.objects.push(Some(value.clone()));
+        value
+    }
+
+    fn read_traits(&mut self, header: u32) -> Result<Traits, Error> {
+        if header & 1 == 0 {
+            let index = header >> 1;
+            let traits = self
+                .traits
+                .get(index as usize)
+                .cloned()
+                .ok_or(Error::BadReference(index))?;
+            let names = traits.sealed.iter().map(String::len).sum::<usize>();
+            self.copy(traits.class_name.len() + names)?;
+            return Ok(traits);
+        }
+        let traits = if header & 2 == 2 {
+            Traits {
+                class_name: self.read_string()?,
+                externalizable: true,
+                ..Default::default()
+            }
+        } else {
+            let dynamic = header & 4 == 4;
+            let count = header >> 3;
+            let class_name = self.read_string()?;
+            let mut sealed = Vec::new();
+            for _ in 0..count {
+                sealed.push(self.read_string()?);
+            }
+            Traits {
+                class_name,
+                sealed,
+                dynamic,
+                externalizable: false,
+            }
+        };
+        self.traits.push(traits.clone());
+        Ok(traits)
+    }
+
+    fn read_object(&mut self, start: usize, header: u32) -> Result<Value, Error> {
+        let traits = self.read_traits(header)?;
+        let index = self.reserve(start);
+        let mut object = Object {
+            traits,
+            ..Default::default()
+        };
+        if object.traits.externalizable {
+            if !EXTERNALIZABLE_CLASSES.contains(&object.traits.class_name.as_str()) {
+                return Err(Error::Message(format!(
+                    "unsupported externalizable class {}",
+                    object.traits.class_name
+                )));
+            }
+            object.external = Some(Box::new(self.decode()?));
+        } else {
+            for _ in 0..object.traits.sealed.len() {
+                object.sealed.push(self.decode()?);
+            }
+            if object.traits.dynamic {
+                object.dynamic = self.read_pairs()?;
+            }
+        }
+        Ok(self.fill(index, Value::Object(object)))
+    }
+
+    // name, value pairs ended by the empty string
+    fn read_pairs(&mut self) -> Result<Vec<(String, Value)>, Error> {
+        let mut pairs = Vec::new();
+        loop {
+            let name = self.read_string()?;
+            if name.is_empty() {
+                return Ok(pairs);
+            }
+            pairs.push((name, self.decode()?));
+        }
+    }
+
+    pub fn decode(&mut self) -> Result<Value, Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        self.depth += 1;
+        self.deepest = self.deepest.max(self.depth);
+        let value = self.decode_value();
+        self.depth -= 1;
+        value
+    }
+
+    fn decode_value(&mut self) -> Result<Value, Error> {
+        let start = self.expanded();
+        let marker_byte = self.read_u8()?;
+        let marker = Marker::from_u8(marker_byte).ok_or(Error::UnexpectedMarker(marker_byte))?;
+        trace!(target: "amf3::decode", "marker: {:?}", marker);
+        let value = match marker {
+            Marker::Undefined => Value::Undefined,
+            Marker::Null => Value::Null,
+            Marker::False => Value::Boolean(false),
+            Marker::True => Value::Boolean(true),
+            Marker::Integer => {
+                // sign extend from 29 bits
+                let u = self.read_u29()?;
+                Value::Integer(((u << 3) as i32) >> 3)
+            }
+            Marker::Double => Value::Double(self.read_f64()?),
+            Marker::String => Value::String(self.read_string()?),
+            Marker::XmlDocument | Marker::Xml => match self.read_object_header()? {
+                Err(value) => value,
+                Ok(len) => {
+                    let s = self.read_utf8(len as usize)?;
+                    if marker == Marker::Xml {
+                        self.register(start, Value::Xml(s))
+                    } else {
+                        self.register(start, Value::XmlDocument(s))
+                    }
+                }
+            },
+            Marker::Date => match self.read_object_header()? {
+                Err(value) => value,
+                Ok(_) => {
+                    let ms = self.read_f64()?;
+                    self.register(start, Value::Date(ms))
+                }
+            },
+            Marker::Array => match self.read_object_header()? {
+                Err(value) => value,
+                Ok(len) => {
+                    let index = self.reserve(start);
+                    let assoc = self.read_pairs()?;
+                    let mut dense = Vec::new();
+                    for _ in 0..len {
+                        dense.push(self.decode()?);
+                    }
+                    self.fill(index, Value::Array { assoc, dense })
+                }
+            },
+            Marker::Object => match self.read_object_header()? {
+                Err(value) => value,
+                Ok(header) => self.read_object(start, header)?,
+            },
+            Marker::ByteArray => match self.read_object_header()? {
+                Err(value) => value,
+                Ok(len) => {
+                    let bytes = self.take(len as usize)?.to_vec();
+                    self.register(start, Value::ByteArray(bytes))
+                }
+            },
+            Marker::VectorInt | Marker::VectorUint | Marker::VectorDouble => {
+                match self.read_object_header()? {
+                    Err(value) => value,
+                    Ok(len) => {
+                        let fixed = self.read_u8()? != 0;
+                        let value = match marker {
+                            Marker::VectorInt => {
+                                let mut items = Vec::new();
+                                for _ in 0..len {
+                                    items.push(self.read_u32()? as i32);
+                                }
+                                Value::VectorInt { fixed, items }
+                            }
+                            Marker::VectorUint => {
+                                let mut items = Vec::new();
+                                for _ in 0..len {
+                                    items.push(self.read_u32()?);
+                                }
+                                Value::VectorUint { fixed, items }
+                            }
+                            _ => {
+                                let mut items = Vec::new();
+                                for _ in 0..len {
+                                    items.push(self.read_f64()?);
+                                }
+                                Value::VectorDouble { fixed, items }
+                            }
+                        };
+                        self.register(start, value)
+                    }
+                }
+            }
+            Marker::VectorObject => match self.read_object_header()? {
+                Err(value) => value,
+                Ok(len) => {
+                    let index = self.reserve(start);
+                    let fixed = self.read_u8()? != 0;
+                    let type_name = self.read_string()?;
+                    let mut items = Vec::new();
+                    for _ in 0..len {
+                        items.push(self.decode()?);
+                    }
+                    self.fill(
+                        index,
+                        Value::VectorObject {
+                            fixed,
+                            type_name,
+                            items,
+                        },
+                    )
+                }
+            },
+            Marker::Dictionary => match self.read_object_header()? {
+                Err(value) => value,
+                Ok(len) => {
+                    let index = self.reserve(start);
+                    let weak_keys = self.read_u8()? != 0;
+                    let mut entries = Vec::new();
+                    for _ in 0..len {
+                        let key = self.decode()?;
+                        entries.push((key, self.decode()?));
+                    }
+                    self.fill(index, Value::Dictionary { weak_keys, entries })
+                }
+            },
+        };
+        Ok(value)
+    }
+}
+
+/// Writes AMF3 values, sending repeated strings and traits as references
+/// to the first copy.  Other values are always written in full, as finding
+/// equal ones would mean comparing each with everything written before.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+    strings: HashMap<String, usize>,
+    traits: Vec<Traits>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    // value must be less than 2^29
+    fn write_u29(&mut self, value: u32) {
+        let value = value & 0x1fff_ffff;
+        if value < 0x80 {
+            self.buf.push(value as u8);
+        } else if value < 0x4000 {
+            self.buf.push((value >> 7) as u8 | 0x80);
+            self.buf.push((value & 0x7f) as u8);
+        } else if value < 0x20_0000 {
+            self.buf.push((value >> 14) as u8 | 0x80);
+            self.buf.push(((value >> 7) & 0x7f) as u8 | 0x80);
+            self.buf.push((value & 0x7f) as u8);
+        } else {
+            self.buf.push((value >> 22) as u8 | 0x80);
+            self.buf.push(((value >> 15) & 0x7f) as u8 | 0x80);
+            self.buf.push(((value >> 8) & 0x7f) as u8 | 0x80);
+            self.buf.push((value & 0xff) as u8);
+        }
+    }
+
+    // inline length or count, with the low bit set
+    fn write_inline(&mut self, len: usize) -> Result<(), Error> {
+        if len > INTEGER_MAX as usize {
+            return Err(Error::TooLong(len));
+        }
+        self.write_u29(((len as u32) << 1) | 1);
+        Ok(())
+    }
+
+    fn write_string(&mut self, s: &str) -> Result<(), Error> {
+        if s.is_empty() {
+            return self.write_inline(0);
+        }
+        if let Some(&index) = self.strings.get(s) {
+            self.write_u29((index as u32) << 1);
+            return Ok(());
+        }
+        self.write_inline(s.len())?;
+        self.strings.insert(s.to_string(), self.strings.len());
+        self.buf.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn write_traits(&mut self, traits: &Traits) -> Result<(), Error> {
+        if let Some(index) = self.traits.iter().position(|t| t == traits) {
+            self.write_u29(((index as u32) << 2) | 1);
+            return Ok(());
+        }
+        if traits.externalizable {
+            self.write_u29(0x07);
+        } else {
+            // the count shares the U29 with 4 flag bits
+            let count = traits.sealed.len();
+            if count > INTEGER_MAX as usize >> 3 {
+                return Err(Error::TooLong(count));
+            }
+            let dynamic = if traits.dynamic { 0x08 } else { 0 };
+            self.write_u29(((count as u32) << 4) | dynamic | 0x03);
+        }
+        self.traits.push(traits.clone());
+        self.write_string(&traits.class_name)?;
+        if !traits.externalizable {
+            for name in &traits.sealed {
+                self.write_string(name)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_pairs(&mut self, pairs: &[(String, Value)]) -> Result<(), Error> {
+        for (name, value) in pairs {
+            self.write_string(name)?;
+            self.encode(value)?;
+        }
+        self.write_string("")
+    }
+
+    /// fails for strings, arrays and the like with 2^28 or more items
+    pub fn encode(&mut self, value: &Value) -> Result<(), Error> {
+        match value {
+            Value::Undefined => self.buf.push(Marker::Undefined as u8),
+            Value::Null => self.buf.push(Marker::Null as u8),
+            Value::Boolean(false) => self.buf.push(Marker::False as u8),
+            Value::Boolean(true) => self.buf.push(Marker::True as u8),
+            Value::Integer(i) if (INTEGER_MIN..=INTEGER_MAX).contains(i) => {
+                self.buf.push(Marker::Integer as u8);
+                self.write_u29(*i as u32);
+            }
+            Value::Integer(i) => self.encode(&Value::Double(f64::from(*i)))?,
+            Value::Double(n) => {
+                self.buf.push(Marker::Double as u8);
+                self.buf.extend_from_slice(&n.to_be_bytes());
+            }
+            Value::String(s) => {
+                self.buf.push(Marker::String as u8);
+                self.write_string(s)?;
+            }
+            Value::XmlDocument(s) | Value::Xml(s) => {
+                let marker = match value {
+                    Value::Xml(..) => Marker::Xml,
+                    _ => Marker::XmlDocument,
+                };
+                self.buf.push(marker as u8);
+                self.write_inline(s.len())?;
+                self.buf.extend_from_slice(s.as_bytes());
+            }
+            Value::Date(ms) => {
+                self.buf.push(Marker::Date as u8);
+                self.write_inline(0)?;
+                self.buf.extend_from_slice(&ms.to_be_bytes());
+            }
+            Value::Array { assoc, dense } => {
+                self.buf.push(Marker::Array as u8);
+                self.write_inline(dense.len())?;
+                self.write_pairs(assoc)?;
+                for item in dense {
+                    self.encode(item)?;
+                }
+            }
+            Value::Object(object) => {
+                self.buf.push(Marker::Object as u8);
+                self.write_traits(&object.traits)?;
+                if object.traits.externalizable {
+                    if let Some(external) = &object.external {
+                        self.encode(external)?;
+                    }
+                } else {
+                    for item in &object.sealed {
+                        self.encode(item)?;
+                    }
+                    if object.traits.dynamic {
+                        self.write_pairs(&object.dynamic)?;
+                    }
+                }
+            }
+            Value::ByteArray(bytes) => {
+                self.buf.push(Marker::ByteArray as u8);
+                self.write_inline(bytes.len())?;
+                self.buf.extend_from_slice(bytes);
+            }
+            Value::VectorInt { fixed, items } => {
+                self.buf.push(Marker::VectorInt as u8);
+                self.write_inline(items.len())?;
+                self.buf.push(*fixed as u8);
+                for item in items {
+                    self.buf.extend_from_slice(&item.to_be_bytes());
+                }
+            }
+            Value::VectorUint { fixed, items } => {
+                self.buf.push(Marker::VectorUint as u8);
+                self.write_inline(items.len())?;
+                self.buf.push(*fixed as u8);
+                for item in items {
+                    self.buf.extend_from_slice(&item.to_be_bytes());
+                }
+            }
+            Value::VectorDouble { fixed, items } => {
+                self.buf.push(Marker::VectorDouble as u8);
+                self.write_inline(items.len())?;
+                self.buf.push(*fixed as u8);
+                for item in items {
+                    self.buf.extend_from_slice(&item.to_be_bytes());
+                }
+            }
+            Value::VectorObject {
+                fixed,
+                type_name,
+                items,
+            } => {
+                self.buf.push(Marker::VectorObject as u8);
+                self.write_inline(items.len())?;
+                self.buf.push(*fixed as u8);
+                self.write_string(type_name)?;
+                for item in items {
+                    self.encode(item)?;
+                }
+            }
+            Value::Dictionary { weak_keys, entries } => {
+                self.buf.push(Marker::Dictionary as u8);
+                self.write_inline(entries.len())?;
+                self.buf.push(*weak_keys as u8);
+                for (key, item) in entries {
+                    self.encode(key)?;
+                    self.encode(item)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Value {
+    /// decodes one value from the start of the bytes, returning it with the
+    /// number of bytes used
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Value, usize), Error> {
+        let mut decoder = Decoder::new(bytes);
+        let value = decoder.decode()?;
+        Ok((value, decoder.position()))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut encoder = Encoder::new();
+        encoder.encode(self)?;
+        Ok(encoder.into_inner())
+    }
+
+    pub async fn read<T>(reader: T) -> io::Result<Value>
+    where
+        T: AsyncRead + Unpin,
+    {
+        crate::amf::read_incrementally(reader, |bytes| Decoder::new(bytes).decode()).await
+    }
+
+    pub async fn write<T>(mut writer: T, value: Value) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        writer.write_all(&value.to_bytes()?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::bytes_from_hex_string;
+
+    fn assert_round_trip(hex: &str, value: Value) {
+        let bytes = bytes_from_hex_string(hex);
+        assert_eq!(
+            Value::from_bytes(&bytes).expect("decode"),
+            (value.clone(), bytes.len())
+        );
+        assert_eq!(value.to_bytes().expect("encode"), bytes);
+    }
+
+    #[test]
+    fn can_read_and_write_integers() {
+        assert_round_trip("04 00", Value::Integer(0));
+        assert_round_trip("04 7f", Value::Integer(127));
+        assert_round_trip("04 81 00", Value::Integer(128));
+        assert_round_trip("04 ff 7f", Value::Integer(16383));
+        assert_round_trip("04 81 80 00", Value::Integer(16384));
+        assert_round_trip("04 bf ff ff ff", Value::Integer(INTEGER_MAX));
+        assert_round_trip("04 ff ff ff ff", Value::Integer(-1));
+        assert_round_trip("04 c0 80 80 00", Value::Integer(INTEGER_MIN));
+    }
+
+    #[test]
+    fn cannot_write_lengths_over_u29() {
+        let mut encoder = Encoder::new();
+        assert_eq!(encoder.write_inline(1 << 28), Err(Error::TooLong(1 << 28)));
+        assert_eq!(encoder.write_inline((1 << 28) - 1), Ok(()));
+    }
+
+    #[test]
+    fn writes_large_integers_as_double() {
+        assert_eq!(
+            Value::Integer(INTEGER_MAX + 1).to_bytes().expect("encode"),
+            bytes_from_hex_string("05 41 b0 00 00 00 00 00 00")
+        );
+    }
+
+    #[test]
+    fn can_read_and_write_string_references() {
+        // array of 2, no associative part, "hello" then a reference to it
+        assert_round_trip(
+            "09 05 01 06 0b 68 65 6c 6c 6f 06 00",
+            Value::Array {
+                assoc: Vec::new(),
+                dense: vec![
+                    Value::String("hello".to_string()),
+                    Value::String("hello".to_string()),
+                ],
+            },
+        );
+    }
+
+    #[test]
+    fn can_read_and_write_dynamic_object() {
+        // inline dynamic traits, anonymous class, a = 1
+        let object = Object {
+            traits: Traits {
+                dynamic: true,
+                ..Default::default()
+            },
+            dynamic: vec![("a".to_string(), Value::Integer(1))],
+            ..Default::default()
+        };
+        assert_round_trip("0a 0b 01 03 61 04 01 01", Value::Object(object));
+    }
+
+    #[test]
+    fn can_read_and_write_object_and_traits_references() {
+        let traits = Traits {
+            class_name: "Point".to_string(),
+            sealed: vec!["x".to_string(), "y".to_string()],
+            ..Default::default()
+        };
+        let point = |x, y| {
+            Value::Object(Object {
+                traits: traits.clone(),
+                sealed: vec![Value::Integer(x), Value::Integer(y)],
+                ..Default::default()
+            })
+        };
+        let value = Value::Array {
+            assoc: Vec::new(),
+            dense: vec![point(1, 2), point(3, 4), point(1, 2)],
+        };
+        // 0a 23 ...    Point with 2 sealed members x and y, values 1 and 2
+        // 0a 01 ...    traits reference 0, values 3 and 4
+        // 0a 02        object reference 1 (the array itself is 0)
+        let bytes = bytes_from_hex_string(
+            "09 07 01
+            0a 23 0b 50 6f 69 6e 74 03 78 03 79 04 01 04 02
+            0a 01 04 03 04 04
+            0a 02",
+        );
+        assert_eq!(
+            Value::from_bytes(&bytes).expect("decode"),
+            (value.clone(), bytes.len())
+        );
+        // objects are written in full, traits by reference
+        assert_eq!(
+            value.to_bytes().expect("encode"),
+            bytes_from_hex_string(
+                "09 07 01
+                0a 23 0b 50 6f 69 6e 74 03 78 03 79 04 01 04 02
+                0a 01 04 03 04 04
+                0a 01 04 01 04 02"
+            )
+        );
+    }
+
+    #[test]
+    fn can_read_and_write_date_and_byte_array() {
+        assert_round_trip(
+            "08 01 42 77 48 76 e8 00 00 00",
+            Value::Date(1_600_000_000_000.0),
+        );
+        assert_round_trip("0c 07 01 02 03", Value::ByteArray(vec![1, 2, 3]));
+        assert_round_trip("0b 07 3c 61 3e", Value::Xml("<a>".to_string()));
+    }
+
+    #[test]
+    fn can_read_and_write_vectors_and_dictionary() {
+        assert_round_trip(
+            "0d 05 00 00 00 00 01 ff ff ff ff",
+            Value::VectorInt {
+                fixed: false,
+                items: vec![1, -1],
+            },
+        );
+        assert_round_trip(
+            "10 03 01 01 06 03 61",
+            Value::VectorObject {
+                fixed: true,
+                type_name: String::new(),
+                items: vec![Value::String("a".to_string())],
+            },
+        );
+        assert_round_trip(
+            "11 03 00 06 03 6b 03",
+            Value::Dictionary {
+                weak_keys: false,
+                entries: vec![(Value::String("k".to_string()), Value::Boolean(true))],
+            },
+        );
+    }
+
+    #[test]
+    fn can_read_and_write_externalizable_collection() {
+        let collection = Value::Object(Object {
+            traits: Traits {
+                class_name: "flex.messaging.io.ArrayCollection".to_string(),
+                externalizable: true,
+                ..Default::default()
+            },
+            external: Some(Box::new(Value::Array {
+                assoc: Vec::new(),
+                dense: vec![Value::Integer(1)],
+            })),
+            ..Default::default()
+        });
+        let bytes = collection.to_bytes().expect("encode");
+        assert_eq!(bytes[..2], [0x0a, 0x07]);
+        assert_eq!(
+            Value::from_bytes(&bytes).expect("decode"),
+            (collection, bytes.len())
+        );
+    }
+
+    #[test]
+    fn can_not_read_bad_input() {
+        let err = Value::from_bytes(&[0x06, 0x0b, 0x68]).expect_err("truncated");
+        assert_eq!(err, Error::Truncated(4));
+        let err = Value::from_bytes(&[0x12]).expect_err("marker");
+        assert_eq!(err, Error::UnexpectedMarker(0x12));
+        let err = Value::from_bytes(&[0x06, 0x02]).expect_err("reference");
+        assert_eq!(err, Error::BadReference(1));
+        let err = Value::from_bytes(&[0x06, 0x05, 0xff, 0xfe]).expect_err("utf-8");
+        assert!(matches!(err, Error::InvalidUtf8(_)));
+    }
+
+    #[tokio::test]
+    async fn can_read_from_stream() {
+        let bytes = bytes_from_hex_string("06 0b 68 65 6c 6c 6f 04 01");
+        let mut buf: &[u8] = &bytes;
+        let value = Value::read(&mut buf).await.expect("read");
+        assert_eq!(value, Value::String("hello".to_string()));
+        assert_eq!(buf, [0x04, 0x01]);
+    }
+
+    // an array of arrays, each holding the previous one twice
+    #[test]
+    fn reference_bomb_is_too_large() {
+        let mut bytes = bytes_from_hex_string("09 53 01  09 05 01 01 01");
+        for index in 1..41 {
+            bytes.extend(&[0x09, 0x05, 0x01, 0x09, index << 1, 0x09, index << 1]);
+        }
+        let err = Value::from_bytes(&bytes).expect_err("too large");
+        assert_eq!(err, Error::TooLarge);
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let bytes = bytes_from_hex_string(&"09 03 01 ".repeat(5000));
+        let err = Value::from_bytes(&bytes).expect_err("too deep");
+        assert_eq!(err, Error::TooDeep);
+    }
+
+    // the copy nests deeper than anything read directly
+    #[test]
+    fn rejects_references_nested_too_deeply() {
+        // [[[...null]]], [[[...<the first>]]]
+        let mut bytes = bytes_from_hex_string("09 05 01");
+        bytes.extend(bytes_from_hex_string(&"09 03 01 ".repeat(40)));
+        bytes.push(0x01);
+        bytes.extend(bytes_from_hex_string(&"09 03 01 ".repeat(39)));
+        bytes.extend(&[0x09, 0x02]);
+        let err = Value::from_bytes(&bytes).expect_err("too deep");
+        assert_eq!(err, Error::TooDeep);
+    }
+}
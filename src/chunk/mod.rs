@@ -1,11 +1,15 @@
-use crate::connection::bufreadwriter::BufReadWriter;
-use log::{info, trace, warn};
-use std::convert::TryInto;
-use tokio::prelude::*;
+use bytes::BufMut;
+use log::{info, trace};
 // use rml_amf0::{Amf0Value};
 
 mod signal; // declare module
-pub use signal::Signal; // export Signal as part of this module
+pub use signal::{Event, LimitType, Signal}; // export Signal as part of this module
+
+mod reader;
+pub use reader::{ChunkHeader, ChunkReader};
+
+mod writer;
+pub use writer::ChunkWriter;
 
 // prefer that `signal` and `message` mods be invisible externally,
 // will above syntax do that?
@@ -13,6 +17,7 @@ pub use signal::Signal; // export Signal as part of this module
 //use self::signal::Signal;
 //use crate::chunk::signal::Signal;
 
+use crate::error::Error;
 use crate::message::*;
 
 // the table of constants could be merged with Enum declaration with
@@ -41,68 +46,40 @@ use crate::message::*;
 // }
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::large_enum_variant)] // most chunks are messages, boxing saves nothing
 pub enum Chunk {
     Control(Signal),
     Msg(Message),
 }
 
 impl Chunk {
-    pub async fn read<T>(mut reader: T) -> io::Result<(Chunk, u32)>
-    where
-        T: AsyncRead + Unpin,
-    {
-        let first_byte: u8;
-        first_byte = reader.read_u8().await?;
-
-        let fmt = first_byte >> 6;
-        if fmt != 0 {
-            warn!(target: "chunk::read", "Chunk Type {} unimplemented", fmt)
-        };
-        let header_size: u32 = 12; // TODO: variable size chunk header
-
-        let csid = first_byte & 0x3f;
-        info!(target: "chunk::read", "csid: {}", csid);
-
-        let mut buf: [u8; 6] = [0; 6]; // TODO: variable size chunk header
-        reader.read_exact(&mut buf).await?;
-
-        let ts = [0x00, buf[0], buf[1], buf[2]];
-        let _timestamp: u32 = u32::from_be_bytes(ts);
-
-        let length: u32 = u32::from_be_bytes([0x00, buf[3], buf[4], buf[5]]);
-
-        let type_byte = reader.read_u8().await?;
+    // decode a complete message payload based on its message type id
+    pub fn decode_payload(header: &ChunkHeader, payload: &[u8]) -> Result<Chunk, Error> {
+        let type_byte = header.type_id;
         info!(target: "chunk::read", "message type: {}", type_byte);
-
-        let mut buf: [u8; 4] = [0_u8; 4];
-        reader.read_exact(&mut buf).await?;
-        let message_stream_id = u32::from_le_bytes(buf);
-        info!(target: "chunk::read", "message stream id: {}", message_stream_id);
-
-        // buffer for message payload
-        let mut message_buf: Vec<u8> = vec![0; length as usize];
-
-        let bytes_read = reader
-            .read_exact(&mut message_buf)
-            .await
-            .expect("read chunk message payload");
-        if bytes_read < length as usize {
-            warn!(target: "chunk::read", "bytes_read {} < length {}", bytes_read, length);
-            panic!("expected {} bytes got {} bytes", length, bytes_read);
-        }
-        trace!(target: "chunk::read", "payload: {:02x?}", message_buf);
-        let mut chunk_reader: &[u8] = &message_buf;
+        info!(target: "chunk::read", "message stream id: {}", header.stream_id);
 
         let chunk: Chunk = match type_byte {
-            1..=6 => Chunk::Control(Signal::read(&mut chunk_reader, type_byte).await?),
-            20 => Chunk::Msg(Message {
-                stream_id: message_stream_id,
-                data: Message::read(&mut chunk_reader, type_byte, length).await?,
+            1..=6 => Chunk::Control(Signal::decode(payload, type_byte)?),
+            8 | 9 | 15 | 17 | 18 | 20 => Chunk::Msg(Message {
+                stream_id: header.stream_id,
+                timestamp: header.timestamp,
+                encoding: match type_byte {
+                    15 | 17 => ObjectEncoding::Amf3,
+                    _ => ObjectEncoding::Amf0,
+                },
+                data: Message::decode(payload, type_byte)?,
             }),
-            8..=22 => panic!("unimplemented RTMP message type: {}", type_byte), // TODO: fail at some of these
-            _ => panic!("unexpected chunk type: {}", type_byte),
+            // e.g. shared object and aggregate messages
+            8..=22 => return Err(Error::Unsupported(format!("message type {}", type_byte))),
+            _ => {
+                return Err(Error::Protocol(format!(
+                    "unexpected message type {}",
+                    type_byte
+                )))
+            }
         };
-        Ok((chunk, header_size + length))
+        Ok(chunk)
     }
 
     // 04 00 00 00 00 00 28 14  01 00 00 00               ......(.....
@@ -111,96 +88,61 @@ impl Chunk {
     // 00 00 00 05 02 00 0a 63  61 6d 65 72 61 46 65 65   .......cameraFee
     // 64 02 00 04 4c 49 56 45                            d...LIVE
 
-    pub async fn write<T>(mut writer: T, chunk: Chunk) -> io::Result<u32>
-    where
-        T: AsyncWrite + Unpin,
-    {
+    // serialize the message payload, returning the chunk stream id and the
+    // header fields (other than length) it should be sent with
+    pub fn encode_payload<B: BufMut>(
+        buf: &mut B,
+        chunk: Chunk,
+    ) -> Result<(u32, ChunkHeader), Error> {
         trace!(target: "chunk::write", "{:?}", chunk);
-        let mut bytes_written = 0;
-        let mut buf = Vec::new();
-
         // get header info from message
         // set chunkstream ID based on message type
         match chunk {
-            Chunk::Control(s) => panic!("unimplemented Chunk::Control {:?}", s),
+            Chunk::Control(signal) => {
+                // protocol control messages go on csid 2, message stream 0
+                let header = ChunkHeader {
+                    type_id: signal.signal_type() as u8,
+                    ..Default::default()
+                };
+                signal.encode(buf);
+                Ok((2, header))
+            }
             Chunk::Msg(message) => {
-                let (mut cs_id, msg_type): (u8, u8) = match &message.data {
-                    MessageData::Command(..) => (3, 0x14),
-                    _ => {
-                        warn!("unexpected message type {:?}, using csid=3", message);
-                        (3, 0x14)
-                    }
+                // commands and data for a NetStream use their own chunk stream
+                let command_cs_id = if message.stream_id != 0 { 4 } else { 3 };
+                // like other encoders, audio and video each get a chunk
+                // stream, so interleaving them keeps headers compressed
+                let (cs_id, msg_type): (u32, u8) = match &message.data {
+                    MessageData::Audio(..) => (7, 0x08),
+                    MessageData::Video(..) => (6, 0x09),
+                    MessageData::Data { .. } => match message.encoding {
+                        ObjectEncoding::Amf0 => (command_cs_id, 0x12),
+                        ObjectEncoding::Amf3 => (command_cs_id, 0x0f),
+                    },
+                    // responses and status are commands too
+                    _ => match message.encoding {
+                        ObjectEncoding::Amf0 => (command_cs_id, 0x14),
+                        ObjectEncoding::Amf3 => (command_cs_id, 0x11),
+                    },
                 };
-                let stream_id = message.stream_id;
-                if stream_id != 0 {
-                    cs_id = 4;
-                }
-                // serialize the message into buffer to get its length
-                Message::write(&mut buf, message)
-                    .await
-                    .expect("serialize message");
-                // TODO: handle diff chunk headers/msg types
-                // Type0 has 12 byte header (fmt/csid byte followed by 11 bytes)
-                // example:
-                //   04               Type 0, csid=4
-                //   00 00 00         timestamp ()
-                //   00 00 28         RTMP Message payload length: big endian / network
-                //   14               RTMP Message type
-                //   01 00 00 00      Message Stream ID: little endian
-                let mut header = BufReadWriter::new(Vec::new());
-                header.write_u8(cs_id).await.expect("write csid");
-                let timestamp: u32 = 0; // TODO: get timestamp from message
-                let timestamp_bytes = timestamp.to_be_bytes();
-                header.write_u8(timestamp_bytes[1]).await.expect("ts2");
-                header.write_u8(timestamp_bytes[2]).await.expect("ts1");
-                header.write_u8(timestamp_bytes[3]).await.expect("ts0");
-
-                let msg_len: u32 = buf.len().try_into().unwrap(); // TODO: check for 3
-                trace!(target: "chunk::write", "msg_len: {:?}", msg_len);
-                let len_bytes = msg_len.to_be_bytes();
-                header.write_u8(len_bytes[1]).await.expect("len2");
-                header.write_u8(len_bytes[2]).await.expect("len1");
-                header.write_u8(len_bytes[3]).await.expect("len0");
-
-                header.write_u8(msg_type).await.expect("write type");
-
-                let stream_id_bytes = stream_id.to_le_bytes();
-                header.write(&stream_id_bytes).await.expect("stream id");
-                trace!(target: "chunk::write", "header len: {:?}", header.len());
-                // hack
-                // let hex_str = format!(
-                //   "{:02x} 00 00 00 00 00 {:02x} 14  {:02x} 00 00 00",
-                //   cs_id,
-                //   buf.len(),
-                //   stream_id
-                // );
-                // let chunk_header = util::bytes_from_hex_string(&hex_str);
-                // writer
-                //   .write(&chunk_header)
-                //   .await
-                //   .expect("write chunk header");
-                trace!(target: "chunk::write", "header: {:02x?}", &header.buf);
-                let header_size = writer.write(&header).await.expect("write header");
-                bytes_written += header_size;
-
-                trace!(target: "chunk::write", "payload: {:02x?}", &buf);
-                let data_size = writer.write(&buf).await.expect("write payload");
-                bytes_written += data_size;
-
-                // TODO: use write_exact to detect socket close
+                let header = ChunkHeader {
+                    timestamp: message.timestamp,
+                    type_id: msg_type,
+                    stream_id: message.stream_id,
+                    ..Default::default()
+                };
+                message.encode(buf)?;
+                Ok((cs_id, header))
             } // Chunk::Msg
-        }; // match chunk
-        assert_eq!(bytes_written <= (u32::max_value() as usize), true);
-        Ok(bytes_written as u32)
-    } // fn write
+        } // match chunk
+    } // fn write_payload
 } // impl Chunk
 
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
-    use crate::amf::Value;
-    use std::collections::HashMap;
+    use crate::amf::{Value, ValueMap};
 
     #[tokio::test]
     async fn can_write_connect_message() {
@@ -212,7 +154,7 @@ mod tests {
         // 02 00 07 63 6f 6e 6e 65 63 74 00 3f f0 00 00 00 00 00 00 03 00 03 61 70 70 02 00 09 76 6f 64 2f 6d 65 64 69 61 00 08 66 6c 61 73 68 56 65 72 02 00 0e 4d 41 43 20 31 30 2c 30 2c 33 32 2c 31 38 00 05 74 63 55 72 6c 02 00 1f 72 74 6d 70 3a 2f 2f 31 32 37 2e 30 2e 30 2e 31 3a 31 39 33 35 2f 76 6f 64 2f 6d 65 64 69 61 00 00 09");
         // let chunk_header = bytes_from_hex_string(&hex_str);
 
-        let mut properties = HashMap::new();
+        let mut properties = ValueMap::new();
 
         let app = "vod/media".to_string();
         properties.insert("app".to_string(), Value::Utf8(app));
@@ -227,6 +169,8 @@ mod tests {
 
         let cmd = Message {
             stream_id: 0,
+            timestamp: 0,
+            encoding: ObjectEncoding::Amf0,
             data: MessageData::Command(MessageCommand {
                 name: "connect".to_string(),
                 id: 1.0,
@@ -237,12 +181,63 @@ mod tests {
 
         let mut buf = Vec::new();
 
-        let num_bytes = Chunk::write(&mut buf, Chunk::Msg(cmd))
+        let num_bytes = ChunkWriter::new()
+            .write(&mut buf, Chunk::Msg(cmd))
             .await
             .expect("write");
         // println!("bytes written: {:02x?}", buf);
         // println!("num bytes written: {:?}", buf.len());
-        assert_eq!(num_bytes, 142);
+        // 12 byte header, 130 byte payload split after 128 bytes by a 1 byte header
+        assert_eq!(num_bytes, 143);
+    }
+
+    #[tokio::test]
+    async fn can_write_chunk_set_chunk_size() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        let num_bytes = ChunkWriter::new()
+            .write(&mut buf, Chunk::Control(Signal::SetChunkSize(4096)))
+            .await
+            .expect("write");
+        assert_eq!(
+            buf,
+            bytes_from_hex_string("02 00 00 00 00 00 04 01 00 00 00 00 00 00 10 00")
+        );
+        assert_eq!(num_bytes, 16);
+    }
+
+    #[tokio::test]
+    async fn can_write_audio_and_video_on_own_chunk_streams() {
+        let mut writer = ChunkWriter::new();
+        let mut reader = ChunkReader::new();
+        let mut audio = Message::new(Some(1), MessageData::Audio(vec![0xaf, 0x01, 0x21]));
+        audio.timestamp = 23;
+        let mut video = Message::new(Some(1), MessageData::Video(vec![0x17, 0x01, 0x00]));
+        video.timestamp = 40;
+
+        let mut buf = Vec::new();
+        writer
+            .write(&mut buf, Chunk::Msg(audio.clone()))
+            .await
+            .expect("write");
+        assert_eq!(buf[0], 0x07);
+        assert_eq!(buf[7], 0x08);
+
+        let mut bytes: &[u8] = &buf;
+        let (chunk, _) = reader.read(&mut bytes).await.expect("read");
+        assert_eq!(chunk, Chunk::Msg(audio));
+
+        let mut buf = Vec::new();
+        writer
+            .write(&mut buf, Chunk::Msg(video.clone()))
+            .await
+            .expect("write");
+        assert_eq!(buf[0], 0x06);
+        assert_eq!(buf[7], 0x09);
+
+        let mut bytes: &[u8] = &buf;
+        let (chunk, _) = reader.read(&mut bytes).await.expect("read");
+        assert_eq!(chunk, Chunk::Msg(video));
     }
 
     #[tokio::test]
@@ -252,7 +247,7 @@ mod tests {
         let bytes = bytes_from_hex_string("02 00 00 00 00 00 04 05 00 00 00 00 00 26 25 a0");
 
         let buf: &[u8] = &bytes;
-        let (chunk, num_bytes) = Chunk::read(buf).await.expect("read");
+        let (chunk, num_bytes) = ChunkReader::new().read(buf).await.expect("read");
         assert_eq!(chunk, Chunk::Control(Signal::SetWindowAckSize(2500000)));
         assert_eq!(num_bytes, 16);
     }
@@ -263,7 +258,7 @@ mod tests {
         let bytes = bytes_from_hex_string("02 00 00 00 00 00 05 06 00 00 00 00 00 26 25 a0 02");
 
         let buf: &[u8] = &bytes;
-        let (chunk, num_bytes) = Chunk::read(buf).await.expect("read");
+        let (chunk, num_bytes) = ChunkReader::new().read(buf).await.expect("read");
         assert_eq!(chunk, Chunk::Control(Signal::SetPeerBandwidth(2500000, 2)));
         assert_eq!(num_bytes, 17);
     }
@@ -291,7 +286,7 @@ mod tests {
     //   09 00 00 09");
 
     //   let buf: &[u8] = &bytes;
-    //   let (chunk, num_bytes) = Chunk::read(buf).await.expect("read");
+    //   let (chunk, num_bytes) = ChunkReader::new().read(buf).await.expect("read");
     //   // TODO
     //   // let value = Value::Utf8String("test".to_string());
     //   // // assert_eq!(chunk, Chunk::Msg(Message::Command(value)));
@@ -0,0 +1,393 @@
+use log::{info, trace, warn};
+use std::collections::HashMap;
+#[cfg(test)]
+use tokio::prelude::*;
+
+use super::Chunk;
+use crate::error::Error;
+
+// size of each read from the underlying transport
+#[cfg(test)]
+const READ_BUF_SIZE: usize = 4096;
+
+// until the peer sends SetChunkSize, chunks carry at most 128 bytes of payload
+pub const DEFAULT_CHUNK_SIZE: u32 = 128;
+
+// a 24 bit timestamp field with this value means the real timestamp follows
+// the message header as a 4 byte extended timestamp
+pub const EXTENDED_TIMESTAMP: u32 = 0x00ff_ffff;
+
+/// Message header fields for a chunk stream, remembered so that chunks with
+/// compressed headers (type 1, 2 and 3) can inherit what they leave out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkHeader {
+    pub timestamp: u32, // absolute timestamp of the current message
+    pub timestamp_delta: u32,
+    pub length: u32,
+    pub type_id: u8,
+    pub stream_id: u32,
+    // whether chunks on this stream carry an extended timestamp
+    pub extended: bool,
+}
+
+// per chunk stream id state
+#[derive(Debug, Default)]
+struct ChunkStream {
+    header: ChunkHeader,
+    payload: Vec<u8>, // bytes received so far for the current message
+}
+
+/// Reads chunks from a transport and reassembles them into messages.
+///
+/// Messages larger than the inbound chunk size arrive split across several
+/// chunks, possibly interleaved with chunks of other chunk streams, so the
+/// partially received payload is kept per chunk stream id until complete.
+///
+/// Bytes are pulled from the transport into an internal buffer and a chunk is
+/// only consumed once it is complete, so a `read` future that is dropped part
+/// way through (e.g. by `tokio::select!`) does not lose data.  Without a
+/// transport, bytes can be given with `push` and messages taken with `decode`.
+#[derive(Debug)]
+pub struct ChunkReader {
+    streams: HashMap<u32, ChunkStream>,
+    chunk_size: u32,
+    buf: Vec<u8>,
+    consumed: u32, // bytes of chunks decoded towards the next message
+}
+
+impl Default for ChunkReader {
+    fn default() -> Self {
+        Self {
+            streams: Default::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            buf: Vec::new(),
+            consumed: 0,
+        }
+    }
+}
+
+impl ChunkReader {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// set maximum payload size of inbound chunks, as requested by the peer
+    /// with a SetChunkSize message
+    pub fn set_chunk_size(&mut self, size: u32) {
+        // only 31 bits are valid, and a chunk must carry at least 1 byte
+        self.chunk_size = (size & 0x7fff_ffff).max(1);
+    }
+
+    /// discard a partially received message, as requested by the peer
+    /// with an Abort message
+    pub fn abort(&mut self, csid: u32) {
+        if let Some(stream) = self.streams.get_mut(&csid) {
+            stream.payload.clear();
+        }
+    }
+
+    /// returns the next complete message along with the number of bytes
+    /// consumed from the transport to get it
+    #[cfg(test)]
+    pub async fn read<T>(&mut self, mut reader: T) -> Result<(Chunk, u32), Error>
+    where
+        T: AsyncRead + Unpin,
+    {
+        loop {
+            if let Some((chunk, num_bytes)) = self.decode()? {
+                return Ok((chunk?, num_bytes));
+            }
+            let mut read_buf = [0_u8; READ_BUF_SIZE];
+            let bytes_read = reader.read(&mut read_buf).await?;
+            if bytes_read == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.push(&read_buf[..bytes_read]);
+        }
+    }
+
+    /// adds bytes received from the transport
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// returns the next complete message if the bytes pushed so far hold
+    /// one, along with the number of bytes it took.  A message that can't
+    /// be decoded still took those bytes, so its error comes with the count.
+    #[allow(clippy::type_complexity)]
+    pub fn decode(&mut self) -> Result<Option<(Result<Chunk, Error>, u32)>, Error> {
+        while let Some((chunk_bytes, message)) = self.decode_chunk()? {
+            self.consumed += chunk_bytes;
+            if let Some((header, payload)) = message {
+                trace!(target: "chunk::read", "payload: {:02x?}", payload);
+                let num_bytes = std::mem::take(&mut self.consumed);
+                let chunk = Chunk::decode_payload(&header, &payload);
+                return Ok(Some((chunk, num_bytes)));
+            }
+        }
+        Ok(None)
+    }
+
+    // If the buffer holds a complete chunk, consume it and update the state for
+    // its chunk stream.  Returns the size of the chunk, along with the message
+    // header and payload if this chunk completed a message.
+    #[allow(clippy::type_complexity)]
+    fn decode_chunk(&mut self) -> Result<Option<(u32, Option<(ChunkHeader, Vec<u8>)>)>, Error> {
+        let buf = &self.buf;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let fmt = buf[0] >> 6;
+        // basic header is 1, 2 or 3 bytes depending on csid range
+        let (csid, basic_size): (u32, usize) = match buf[0] & 0x3f {
+            0 => {
+                if buf.len() < 2 {
+                    return Ok(None);
+                }
+                (64 + buf[1] as u32, 2)
+            }
+            1 => {
+                if buf.len() < 3 {
+                    return Ok(None);
+                }
+                (64 + buf[1] as u32 + ((buf[2] as u32) << 8), 3)
+            }
+            id => (id as u32, 1),
+        };
+        let message_header_size = match fmt {
+            0 => 11,
+            1 => 7,
+            2 => 3,
+            _ => 0,
+        };
+        let mut header_size = basic_size + message_header_size;
+        if buf.len() < header_size {
+            return Ok(None);
+        }
+        let (mut header, received) = match (fmt, self.streams.get(&csid)) {
+            (0, prev) => (ChunkHeader::default(), prev.map_or(0, |p| p.payload.len())),
+            (_, Some(prev)) => (prev.header.clone(), prev.payload.len()),
+            (_, None) => {
+                return Err(Error::Protocol(format!(
+                    "chunk type {} on csid {} with no previous header",
+                    fmt, csid
+                )))
+            }
+        };
+        if received > 0 && fmt != 3 {
+            warn!(target: "chunk::read", "csid {}: new message header before previous message was complete, discarding {} bytes", csid, received);
+        }
+        // a type 3 chunk continues a partially received message
+        let continuation = fmt == 3 && received > 0;
+        let fields = &buf[basic_size..header_size];
+        // type 0, 1 and 2 chunks carry a timestamp (or delta), type 3 chunks
+        // repeat the extended timestamp of the chunk they follow
+        let mut ts = 0;
+        if fmt <= 2 {
+            ts = u32::from_be_bytes([0x00, fields[0], fields[1], fields[2]]);
+            header.extended = ts == EXTENDED_TIMESTAMP;
+        }
+        if header.extended {
+            if buf.len() < header_size + 4 {
+                return Ok(None);
+            }
+            let ext = &buf[header_size..header_size + 4];
+            ts = u32::from_be_bytes([ext[0], ext[1], ext[2], ext[3]]);
+            header_size += 4;
+        }
+        if fmt <= 2 {
+            if fmt == 0 {
+                // a following type 3 chunk uses the type 0 timestamp as its delta
+                header.timestamp = ts;
+                header.timestamp_delta = ts;
+            } else {
+                header.timestamp_delta = ts;
+                header.timestamp = header.timestamp.wrapping_add(ts);
+            }
+        } else if !continuation {
+            header.timestamp = header.timestamp.wrapping_add(header.timestamp_delta);
+        }
+        if fmt <= 1 {
+            header.length = u32::from_be_bytes([0x00, fields[3], fields[4], fields[5]]);
+            header.type_id = fields[6];
+        }
+        if fmt == 0 {
+            header.stream_id = u32::from_le_bytes([fields[7], fields[8], fields[9], fields[10]]);
+        }
+
+        let received = if continuation { received } else { 0 };
+        let remaining = header.length as usize - received;
+        let payload_size = remaining.min(self.chunk_size as usize);
+        if buf.len() < header_size + payload_size {
+            return Ok(None);
+        }
+        trace!(target: "chunk::read", "fmt: {} csid: {} header: {:?} chunk payload: {}", fmt, csid, header, payload_size);
+        self.buf.drain(..header_size);
+        let stream = self.streams.entry(csid).or_default();
+        if !continuation {
+            stream.payload.clear();
+        }
+        stream.header = header;
+        stream.payload.extend(self.buf.drain(..payload_size));
+
+        let chunk_bytes = (header_size + payload_size) as u32;
+        if stream.payload.len() < stream.header.length as usize {
+            return Ok(Some((chunk_bytes, None)));
+        }
+        info!(target: "chunk::read", "csid: {} message header: {:?}", csid, stream.header);
+        let payload = std::mem::take(&mut stream.payload);
+        Ok(Some((chunk_bytes, Some((stream.header.clone(), payload)))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Signal;
+    use crate::util::bytes_from_hex_string;
+
+    #[tokio::test]
+    async fn can_read_type_1_2_and_3_headers() {
+        let bytes = bytes_from_hex_string(
+            "02 00 00 10 00 00 04 05 00 00 00 00  00 26 25 a0
+             42 00 00 05 00 00 04 01              00 00 10 00
+             82 00 00 05                          00 00 00 80
+             c2                                   00 00 00 ff",
+        );
+        let mut buf: &[u8] = &bytes;
+        let mut reader = ChunkReader::new();
+
+        let (chunk, num_bytes) = reader.read(&mut buf).await.expect("type 0");
+        assert_eq!(chunk, Chunk::Control(Signal::SetWindowAckSize(2500000)));
+        assert_eq!(num_bytes, 16);
+
+        let (chunk, num_bytes) = reader.read(&mut buf).await.expect("type 1");
+        assert_eq!(chunk, Chunk::Control(Signal::SetChunkSize(4096)));
+        assert_eq!(num_bytes, 12);
+
+        let (chunk, num_bytes) = reader.read(&mut buf).await.expect("type 2");
+        assert_eq!(chunk, Chunk::Control(Signal::SetChunkSize(128)));
+        assert_eq!(num_bytes, 8);
+
+        let (chunk, num_bytes) = reader.read(&mut buf).await.expect("type 3");
+        assert_eq!(chunk, Chunk::Control(Signal::SetChunkSize(255)));
+        assert_eq!(num_bytes, 5);
+
+        assert_eq!(reader.streams[&2].header.timestamp, 0x10 + 5 + 5 + 5);
+    }
+
+    #[tokio::test]
+    async fn can_read_two_and_three_byte_basic_headers() {
+        let bytes = bytes_from_hex_string(
+            "00 00     00 00 00 00 00 04 01 00 00 00 00  00 00 10 00
+             01 ff 01  00 00 00 00 00 04 01 00 00 00 00  00 00 00 80",
+        );
+        let mut buf: &[u8] = &bytes;
+        let mut reader = ChunkReader::new();
+
+        let (_, num_bytes) = reader.read(&mut buf).await.expect("2 byte basic header");
+        assert_eq!(num_bytes, 17);
+        assert!(reader.streams.contains_key(&64));
+
+        let (_, num_bytes) = reader.read(&mut buf).await.expect("3 byte basic header");
+        assert_eq!(num_bytes, 18);
+        assert!(reader.streams.contains_key(&(64 + 0xff + 0x100)));
+    }
+
+    #[tokio::test]
+    async fn can_reassemble_interleaved_chunks() {
+        let bytes = bytes_from_hex_string(
+            "02 00 00 00 00 00 04 05 00 00 00 00  00 26
+             03 00 00 00 00 00 04 01 00 00 00 00  00 00
+             c2                                   25 a0
+             c3                                   10 00",
+        );
+        let mut buf: &[u8] = &bytes;
+        let mut reader = ChunkReader::new();
+        reader.set_chunk_size(2);
+
+        let (chunk, num_bytes) = reader.read(&mut buf).await.expect("read");
+        assert_eq!(chunk, Chunk::Control(Signal::SetWindowAckSize(2500000)));
+        assert_eq!(num_bytes, 14 + 14 + 3);
+
+        let (chunk, num_bytes) = reader.read(&mut buf).await.expect("read");
+        assert_eq!(chunk, Chunk::Control(Signal::SetChunkSize(4096)));
+        assert_eq!(num_bytes, 3);
+    }
+
+    #[tokio::test]
+    async fn can_abort_partial_message() {
+        let bytes = bytes_from_hex_string(
+            "02 00 00 00 00 00 04 05 00 00 00 00  00 26
+             02 00 00 00 00 00 04 01 00 00 00 00  00 00
+             c2                                   00 80",
+        );
+        let mut reader = ChunkReader::new();
+        reader.set_chunk_size(2);
+
+        // first chunk of a message, then the peer gives up on it
+        let mut buf: &[u8] = &bytes[..14];
+        assert!(reader.read(&mut buf).await.is_err());
+        reader.abort(2);
+
+        let mut buf: &[u8] = &bytes[14..];
+        let (chunk, _) = reader.read(&mut buf).await.expect("read");
+        assert_eq!(chunk, Chunk::Control(Signal::SetChunkSize(128)));
+    }
+
+    #[tokio::test]
+    async fn can_read_extended_timestamp() {
+        let bytes = bytes_from_hex_string(
+            "02 ff ff ff 00 00 04 05 00 00 00 00  01 00 00 00  00 26
+             c2                                   01 00 00 00  25 a0
+             42 ff ff ff 00 00 04 01              00 00 00 10  00 00 10 00
+             c2                                   00 00 00 10  00 00 00 80",
+        );
+        let mut buf: &[u8] = &bytes;
+        let mut reader = ChunkReader::new();
+        reader.set_chunk_size(2);
+
+        let (chunk, num_bytes) = reader.read(&mut buf).await.expect("type 0");
+        assert_eq!(chunk, Chunk::Control(Signal::SetWindowAckSize(2500000)));
+        assert_eq!(num_bytes, 18 + 7);
+        assert_eq!(reader.streams[&2].header.timestamp, 0x0100_0000);
+
+        reader.set_chunk_size(128);
+        let (_, num_bytes) = reader.read(&mut buf).await.expect("type 1");
+        assert_eq!(num_bytes, 16);
+        assert_eq!(reader.streams[&2].header.timestamp, 0x0100_0010);
+
+        let (_, num_bytes) = reader.read(&mut buf).await.expect("type 3");
+        assert_eq!(num_bytes, 9);
+        assert_eq!(reader.streams[&2].header.timestamp, 0x0100_0020);
+    }
+
+    #[tokio::test]
+    async fn error_on_compressed_header_without_previous() {
+        let bytes = bytes_from_hex_string("c2 00 00 00 ff");
+        let buf: &[u8] = &bytes;
+        let result = ChunkReader::new().read(buf).await;
+        assert!(matches!(result, Err(Error::Protocol(..))));
+    }
+
+    #[test]
+    fn unsupported_message_still_counts_its_bytes() {
+        let bytes = bytes_from_hex_string(
+            "03 00 00 00 00 00 04 16 01 00 00 00  de ad be ef
+             02 00 00 00 00 00 04 01 00 00 00 00  00 00 10 00",
+        );
+        let mut reader = ChunkReader::new();
+        reader.push(&bytes);
+
+        let (chunk, num_bytes) = reader.decode().expect("framing").expect("message");
+        assert!(matches!(chunk, Err(Error::Unsupported(..))));
+        assert_eq!(num_bytes, 16);
+
+        let (chunk, num_bytes) = reader.decode().expect("framing").expect("message");
+        assert_eq!(
+            chunk.expect("chunk"),
+            Chunk::Control(Signal::SetChunkSize(4096))
+        );
+        assert_eq!(num_bytes, 16);
+    }
+}
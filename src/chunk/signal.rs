@@ -1,9 +1,13 @@
+use bytes::BufMut;
+#[cfg(test)]
 use tokio::prelude::*;
 extern crate proc_macro;
 
 extern crate num_traits;
 use num_traits::FromPrimitive;
 
+use crate::error::Error;
+
 #[derive(Debug, PartialEq, Primitive)]
 pub enum EventType {
     StreamBegin = 0,
@@ -16,15 +20,37 @@ pub enum EventType {
     PingResponse = 7,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Event {
-    StreamBegin(u32), // stream_id
-                      // StreamEOF(u32),            // stream_id
-                      // StreamDry(u32),            // stream_id
-                      // SetBufferLength(u32, u32), // stream_id, buffer length in ms
-                      // StreamIsRecorded(u32),     // stream_id
-                      // PingRequest(u32),          // timestamp
-                      // PingResponse(u32),         // timestamp
+    StreamBegin(u32),          // stream_id
+    StreamEOF(u32),            // stream_id
+    StreamDry(u32),            // stream_id
+    SetBufferLength(u32, u32), // stream_id, buffer length in ms
+    StreamIsRecorded(u32),     // stream_id
+    PingRequest(u32),          // timestamp
+    PingResponse(u32),         // timestamp
+}
+
+impl Event {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            Event::StreamBegin(..) => EventType::StreamBegin,
+            Event::StreamEOF(..) => EventType::StreamEOF,
+            Event::StreamDry(..) => EventType::StreamDry,
+            Event::SetBufferLength(..) => EventType::SetBufferLength,
+            Event::StreamIsRecorded(..) => EventType::StreamIsRecorded,
+            Event::PingRequest(..) => EventType::PingRequest,
+            Event::PingResponse(..) => EventType::PingResponse,
+        }
+    }
+}
+
+// how the peer bandwidth in SetPeerBandwidth is applied
+#[derive(Debug, PartialEq, Clone, Copy, Primitive)]
+pub enum LimitType {
+    Hard = 0,    // limit output to the window size
+    Soft = 1,    // limit to the smaller of this and the current limit
+    Dynamic = 2, // treat as hard if the previous limit was hard, else ignore
 }
 
 // TODO: can we just derive Read on these, given that we know type?
@@ -51,76 +77,146 @@ pub enum SignalType {
 }
 use SignalType::*;
 
-impl Signal {
-    // there must be a better way, or put this somewhere else
-    // prolly want to inline to
-    async fn read_le_stream_id<T>(mut reader: T) -> io::Result<u32>
-    where
-        T: AsyncRead + Unpin,
-    {
-        let mut buf: [u8; 4] = [0_u8; 4];
-        reader.read_exact(&mut buf).await.expect("4 byte stream id");
-        Ok(u32::from_le_bytes(buf))
+// fields are big-endian, failing if the payload is too short
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if buf.len() < len {
+        return Err(Error::Protocol(
+            "protocol control message too short".to_string(),
+        ));
     }
+    let (field, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(field)
+}
 
-    async fn read_user_control_message<T>(mut reader: T) -> io::Result<Signal>
-    where
-        T: AsyncRead + Unpin,
-    {
-        let event_type = reader.read_u16().await.expect("read event type");
+fn get_u8(buf: &mut &[u8]) -> Result<u8, Error> {
+    Ok(take(buf, 1)?[0])
+}
+
+fn get_u16(buf: &mut &[u8]) -> Result<u16, Error> {
+    let b = take(buf, 2)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn get_u32(buf: &mut &[u8]) -> Result<u32, Error> {
+    let b = take(buf, 4)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+impl Signal {
+    // event data is big-endian, like the rest of the protocol control messages
+    fn decode_user_control_message(buf: &mut &[u8]) -> Result<Signal, Error> {
+        let event_type = get_u16(buf)?;
         let event: Event = match EventType::from_u16(event_type) {
-        Some(EventType::StreamBegin) => {
-          let stream_id = Signal::read_le_stream_id(reader).await.unwrap();
-          Event::StreamBegin(stream_id)
-        },
-        _ => {
-          panic!("unimplemented event_type {:?}, reading user control message", event_type);
+            Some(EventType::StreamBegin) => Event::StreamBegin(get_u32(buf)?),
+            Some(EventType::StreamEOF) => Event::StreamEOF(get_u32(buf)?),
+            Some(EventType::StreamDry) => Event::StreamDry(get_u32(buf)?),
+            Some(EventType::SetBufferLength) => {
+                let stream_id = get_u32(buf)?;
+                let buffer_length = get_u32(buf)?;
+                Event::SetBufferLength(stream_id, buffer_length)
+            }
+            Some(EventType::StreamIsRecorded) => Event::StreamIsRecorded(get_u32(buf)?),
+            Some(EventType::PingRequest) => Event::PingRequest(get_u32(buf)?),
+            Some(EventType::PingResponse) => Event::PingResponse(get_u32(buf)?),
+            None => {
+                return Err(Error::Protocol(format!(
+                    "unknown user control event type {}",
+                    event_type
+                )))
+            }
+        };
+        Ok(Signal::UserControlMessage(event))
+    }
+
+    pub fn signal_type(&self) -> SignalType {
+        match self {
+            Signal::SetChunkSize(..) => SetChunkSize,
+            Signal::Abort(..) => Abort,
+            Signal::AckChunk(..) => AckChunk,
+            Signal::UserControlMessage(..) => UserControlMessage,
+            Signal::SetWindowAckSize(..) => SetWindowAckSize,
+            Signal::SetPeerBandwidth(..) => SetPeerBandwidth,
         }
-        // StreamEOF = 1,
-        // StreamDry = 2,
-        // SetBufferLength = 3,
-        // StreamIsRecorded = 4,
-        // // what is 5?
-        // PingRequest = 6,
-        // PingResponse = 7,
-    };
+    }
 
-        Ok(Signal::UserControlMessage(event))
+    fn encode_user_control_message<B: BufMut>(buf: &mut B, event: Event) {
+        buf.put_u16(event.event_type() as u16);
+        match event {
+            Event::StreamBegin(stream_id)
+            | Event::StreamEOF(stream_id)
+            | Event::StreamDry(stream_id)
+            | Event::StreamIsRecorded(stream_id) => buf.put_u32(stream_id),
+            Event::SetBufferLength(stream_id, buffer_length) => {
+                buf.put_u32(stream_id);
+                buf.put_u32(buffer_length);
+            }
+            Event::PingRequest(timestamp) | Event::PingResponse(timestamp) => {
+                buf.put_u32(timestamp)
+            }
+        }
+    }
+
+    /// appends the message payload to the buffer
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        match *self {
+            Signal::SetChunkSize(size) => buf.put_u32(size & 0x7fff_ffff),
+            Signal::Abort(csid) => buf.put_u32(csid),
+            Signal::AckChunk(sequence_number) => buf.put_u32(sequence_number),
+            Signal::UserControlMessage(event) => Signal::encode_user_control_message(buf, event),
+            Signal::SetWindowAckSize(window_size) => buf.put_u32(window_size),
+            Signal::SetPeerBandwidth(window_size, limit_type) => {
+                buf.put_u32(window_size);
+                buf.put_u8(limit_type);
+            }
+        }
     }
-    pub async fn read<T>(mut reader: T, chunk_type: u8) -> io::Result<Signal>
+
+    #[cfg(test)]
+    pub async fn write<T>(mut writer: T, signal: Signal) -> io::Result<()>
     where
-        T: AsyncRead + Unpin,
+        T: AsyncWrite + Unpin,
     {
+        let mut buf = Vec::new();
+        signal.encode(&mut buf);
+        writer.write_all(&buf).await
+    }
+
+    /// decodes a complete message payload of the given message type
+    pub fn decode(payload: &[u8], chunk_type: u8) -> Result<Signal, Error> {
+        let buf = &mut &payload[..];
         let signal: Signal = match SignalType::from_u8(chunk_type) {
             // TODO: how to match SignalType
-            Some(SetChunkSize) => {
-                let size = reader.read_u32().await?;
-                Signal::SetChunkSize(size)
-            }
-            Some(Abort) => {
-                let data = reader.read_u32().await?;
-                Signal::Abort(data)
-            }
-            Some(AckChunk) => {
-                let data = reader.read_u32().await?;
-                Signal::AckChunk(data)
-            }
-            Some(UserControlMessage) => Signal::read_user_control_message(reader)
-                .await
-                .expect("read control msg"),
-            Some(SetWindowAckSize) => {
-                let window_size = reader.read_u32().await?;
-                Signal::SetWindowAckSize(window_size)
-            }
+            Some(SetChunkSize) => Signal::SetChunkSize(get_u32(buf)?),
+            Some(Abort) => Signal::Abort(get_u32(buf)?),
+            Some(AckChunk) => Signal::AckChunk(get_u32(buf)?),
+            Some(UserControlMessage) => Signal::decode_user_control_message(buf)?,
+            Some(SetWindowAckSize) => Signal::SetWindowAckSize(get_u32(buf)?),
             Some(SetPeerBandwidth) => {
-                let window_size = reader.read_u32().await?;
-                let limit_type = reader.read_u8().await?; // TODO: make enum
+                let window_size = get_u32(buf)?;
+                let limit_type = get_u8(buf)?; // TODO: make enum
                 Signal::SetPeerBandwidth(window_size, limit_type)
             }
-            _ => panic!("unimplemented read for signal chunk type {}", chunk_type),
+            _ => {
+                return Err(Error::Protocol(format!(
+                    "unexpected signal type {}",
+                    chunk_type
+                )))
+            }
         };
         Ok(signal)
     }
+
+    // reads the rest of the payload from the reader
+    #[cfg(test)]
+    pub async fn read<T>(mut reader: T, chunk_type: u8) -> Result<Signal, Error>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let mut payload = Vec::new();
+        reader.read_to_end(&mut payload).await?;
+        Self::decode(&payload, chunk_type)
+    }
 }
 
 #[cfg(test)]
@@ -162,13 +258,47 @@ mod tests {
     #[tokio::test]
     async fn can_read_user_control_message() {
         use crate::util::bytes_from_hex_string;
-        let bytes = bytes_from_hex_string("00 00 01 00 00 00");
+        let bytes = bytes_from_hex_string("00 00 00 00 00 01");
 
         let buf: &[u8] = &bytes;
         let chunk = (Signal::read(buf, UserControlMessage as u8).await).expect("read");
         assert_eq!(chunk, Signal::UserControlMessage(Event::StreamBegin(1)));
     }
 
+    #[tokio::test]
+    async fn can_read_all_user_control_events() {
+        use crate::util::bytes_from_hex_string;
+        let cases = vec![
+            ("00 01 00 00 00 01", Event::StreamEOF(1)),
+            ("00 02 00 00 00 01", Event::StreamDry(1)),
+            (
+                "00 03 00 00 00 01 00 00 0b b8",
+                Event::SetBufferLength(1, 3000),
+            ),
+            ("00 04 00 00 00 01", Event::StreamIsRecorded(1)),
+            ("00 06 00 01 e2 40", Event::PingRequest(123456)),
+            ("00 07 00 01 e2 40", Event::PingResponse(123456)),
+        ];
+        for (hex, event) in cases {
+            let bytes = bytes_from_hex_string(hex);
+            let buf: &[u8] = &bytes;
+            let chunk = (Signal::read(buf, UserControlMessage as u8).await).expect("read");
+            assert_eq!(chunk, Signal::UserControlMessage(event));
+        }
+    }
+
+    #[tokio::test]
+    async fn can_not_read_unknown_user_control_event() {
+        use crate::util::bytes_from_hex_string;
+        let bytes = bytes_from_hex_string("00 05 00 00 00 01");
+
+        let buf: &[u8] = &bytes;
+        let err = Signal::read(buf, UserControlMessage as u8)
+            .await
+            .expect_err("unknown event");
+        assert!(matches!(err, Error::Protocol(..)));
+    }
+
     #[tokio::test]
     async fn can_read_set_window_ack_size() {
         use crate::util::bytes_from_hex_string;
@@ -179,6 +309,92 @@ mod tests {
         assert_eq!(chunk, Signal::SetWindowAckSize(2500000));
     }
 
+    #[tokio::test]
+    async fn can_write_set_chunk_size() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        Signal::write(&mut buf, Signal::SetChunkSize(4096))
+            .await
+            .expect("write");
+        assert_eq!(buf, bytes_from_hex_string("00 00 10 00"));
+    }
+
+    #[tokio::test]
+    async fn can_write_abort() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        Signal::write(&mut buf, Signal::Abort(4))
+            .await
+            .expect("write");
+        assert_eq!(buf, bytes_from_hex_string("00 00 00 04"));
+    }
+
+    #[tokio::test]
+    async fn can_write_ack_chunk() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        Signal::write(&mut buf, Signal::AckChunk(2500000))
+            .await
+            .expect("write");
+        assert_eq!(buf, bytes_from_hex_string("00 26 25 a0"));
+    }
+
+    #[tokio::test]
+    async fn can_write_user_control_message() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        Signal::write(&mut buf, Signal::UserControlMessage(Event::StreamBegin(1)))
+            .await
+            .expect("write");
+        assert_eq!(buf, bytes_from_hex_string("00 00 00 00 00 01"));
+    }
+
+    #[tokio::test]
+    async fn can_write_set_buffer_length() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        Signal::write(
+            &mut buf,
+            Signal::UserControlMessage(Event::SetBufferLength(1, 3000)),
+        )
+        .await
+        .expect("write");
+        assert_eq!(buf, bytes_from_hex_string("00 03 00 00 00 01 00 00 0b b8"));
+    }
+
+    #[tokio::test]
+    async fn can_write_ping_response() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        Signal::write(
+            &mut buf,
+            Signal::UserControlMessage(Event::PingResponse(123456)),
+        )
+        .await
+        .expect("write");
+        assert_eq!(buf, bytes_from_hex_string("00 07 00 01 e2 40"));
+    }
+
+    #[tokio::test]
+    async fn can_write_set_window_ack_size() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        Signal::write(&mut buf, Signal::SetWindowAckSize(2500000))
+            .await
+            .expect("write");
+        assert_eq!(buf, bytes_from_hex_string("00 26 25 a0"));
+    }
+
+    #[tokio::test]
+    async fn can_write_set_peer_bandwidth() {
+        use crate::util::bytes_from_hex_string;
+        let mut buf = Vec::new();
+        Signal::write(&mut buf, Signal::SetPeerBandwidth(2500000, 2))
+            .await
+            .expect("write");
+        assert_eq!(buf, bytes_from_hex_string("00 26 25 a0 02"));
+    }
+
     #[tokio::test]
     async fn can_read_set_peer_bandwidth() {
         use crate::util::bytes_from_hex_string;
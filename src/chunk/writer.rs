@@ -0,0 +1,297 @@
+use log::trace;
+use std::collections::HashMap;
+#[cfg(test)]
+use tokio::prelude::*;
+
+use crate::error::Error;
+
+use super::reader::{DEFAULT_CHUNK_SIZE, EXTENDED_TIMESTAMP};
+use super::{Chunk, ChunkHeader};
+
+/// Writes messages as chunks, tracking the last header sent per chunk stream id.
+///
+/// Payloads larger than the outbound chunk size are split into a first chunk
+/// followed by type 3 continuation chunks.  When fields repeat from the
+/// previous message on the same chunk stream, a smaller type 1, 2 or 3 header
+/// is used.
+#[derive(Debug)]
+pub struct ChunkWriter {
+    headers: HashMap<u32, ChunkHeader>,
+    chunk_size: u32,
+}
+
+impl Default for ChunkWriter {
+    fn default() -> Self {
+        Self {
+            headers: Default::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+impl ChunkWriter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// set maximum payload size of outbound chunks, the peer must be told
+    /// about the new size with a SetChunkSize message
+    pub fn set_chunk_size(&mut self, size: u32) {
+        // only 31 bits are valid, and a chunk must carry at least 1 byte
+        self.chunk_size = (size & 0x7fff_ffff).max(1);
+    }
+
+    /// returns the number of bytes written to the transport
+    #[cfg(test)]
+    pub async fn write<T>(&mut self, mut writer: T, chunk: Chunk) -> Result<u32, Error>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let mut buf = Vec::new();
+        let num_bytes = self.encode(&mut buf, chunk)?;
+        writer.write_all(&buf).await?;
+        Ok(num_bytes)
+    }
+
+    /// appends the chunks for a message to the buffer, returning the
+    /// number of bytes appended
+    pub fn encode(&mut self, buf: &mut Vec<u8>, chunk: Chunk) -> Result<u32, Error> {
+        let mut payload = Vec::new();
+        let (csid, mut header) = Chunk::encode_payload(&mut payload, chunk)?;
+        header.length = payload.len() as u32;
+        let start = buf.len();
+        buf.reserve(payload.len() + 18);
+        self.encode_chunks(buf, csid, header, &payload);
+        trace!(target: "chunk::write", "bytes: {:02x?}", &buf[start..]);
+        Ok((buf.len() - start) as u32)
+    }
+
+    fn encode_chunks(
+        &mut self,
+        buf: &mut Vec<u8>,
+        csid: u32,
+        mut header: ChunkHeader,
+        payload: &[u8],
+    ) {
+        // pick the smallest header that lets the reader reconstruct all fields
+        let fmt = match self.headers.get(&csid) {
+            Some(prev)
+                if prev.stream_id == header.stream_id && header.timestamp >= prev.timestamp =>
+            {
+                header.timestamp_delta = header.timestamp - prev.timestamp;
+                if prev.length != header.length || prev.type_id != header.type_id {
+                    1
+                } else if prev.timestamp_delta != header.timestamp_delta {
+                    2
+                } else {
+                    3
+                }
+            }
+            _ => {
+                // a following type 3 chunk uses the type 0 timestamp as its delta
+                header.timestamp_delta = header.timestamp;
+                0
+            }
+        };
+        let ts = if fmt == 0 {
+            header.timestamp
+        } else {
+            header.timestamp_delta
+        };
+        // type 3 chunks repeat the delta of the chunk they follow, so they
+        // need an extended timestamp exactly when it did
+        header.extended = ts >= EXTENDED_TIMESTAMP;
+        trace!(target: "chunk::write", "fmt: {} csid: {} header: {:?}", fmt, csid, header);
+
+        write_basic_header(buf, fmt, csid);
+        if fmt <= 2 {
+            let ts_field = ts.min(EXTENDED_TIMESTAMP);
+            buf.extend_from_slice(&ts_field.to_be_bytes()[1..]);
+        }
+        if fmt <= 1 {
+            buf.extend_from_slice(&header.length.to_be_bytes()[1..]);
+            buf.push(header.type_id);
+        }
+        if fmt == 0 {
+            buf.extend_from_slice(&header.stream_id.to_le_bytes());
+        }
+        if header.extended {
+            buf.extend_from_slice(&ts.to_be_bytes());
+        }
+
+        for (index, piece) in payload.chunks(self.chunk_size as usize).enumerate() {
+            if index > 0 {
+                write_basic_header(buf, 3, csid);
+                if header.extended {
+                    buf.extend_from_slice(&ts.to_be_bytes());
+                }
+            }
+            buf.extend_from_slice(piece);
+        }
+        self.headers.insert(csid, header);
+    }
+}
+
+// basic header is 1, 2 or 3 bytes depending on csid range
+fn write_basic_header(buf: &mut Vec<u8>, fmt: u8, csid: u32) {
+    match csid {
+        2..=63 => buf.push((fmt << 6) | csid as u8),
+        64..=319 => {
+            buf.push(fmt << 6);
+            buf.push((csid - 64) as u8);
+        }
+        _ => {
+            let id = csid - 64;
+            buf.push((fmt << 6) | 1);
+            buf.push((id & 0xff) as u8);
+            buf.push((id >> 8) as u8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::Value;
+    use crate::chunk::ChunkReader;
+    use crate::message::*;
+
+    fn command(name: &str) -> Chunk {
+        Chunk::Msg(Message {
+            stream_id: 0,
+            timestamp: 0,
+            encoding: ObjectEncoding::Amf0,
+            data: MessageData::Command(MessageCommand {
+                name: name.to_string(),
+                id: 0.0,
+                data: Value::Null,
+                opt: Vec::new(),
+            }),
+        })
+    }
+
+    #[tokio::test]
+    async fn can_split_payload_into_chunks() {
+        let name = "x".repeat(300);
+        let mut buf = Vec::new();
+        let mut writer = ChunkWriter::new();
+        let num_bytes = writer.write(&mut buf, command(&name)).await.expect("write");
+
+        // payload is 1 + 2 + 300 (name) + 9 (id) + 1 (null) = 313 bytes
+        assert_eq!(num_bytes, 12 + 313 + 2);
+        assert_eq!(buf[12 + 128], 0xc3);
+        assert_eq!(buf[12 + 128 + 1 + 128], 0xc3);
+
+        let bytes: &[u8] = &buf;
+        let (chunk, _) = ChunkReader::new().read(bytes).await.expect("read");
+        assert_eq!(chunk, command(&name));
+    }
+
+    #[tokio::test]
+    async fn can_compress_repeated_headers() {
+        let mut buf = Vec::new();
+        let mut writer = ChunkWriter::new();
+        let first = writer
+            .write(&mut buf, command("first"))
+            .await
+            .expect("write");
+        let same_length = writer
+            .write(&mut buf, command("again"))
+            .await
+            .expect("write");
+        let new_length = writer
+            .write(&mut buf, command("longer"))
+            .await
+            .expect("write");
+        assert_eq!(buf[0], 0x03);
+        assert_eq!(buf[first as usize], 0xc3);
+        assert_eq!(buf[(first + same_length) as usize], 0x43);
+        assert_eq!(same_length, first - 11);
+        assert_eq!(new_length, first + 1 - 4);
+
+        let mut bytes: &[u8] = &buf;
+        let mut reader = ChunkReader::new();
+        for name in &["first", "again", "longer"] {
+            let (chunk, _) = reader.read(&mut bytes).await.expect("read");
+            assert_eq!(chunk, command(name));
+        }
+    }
+
+    #[tokio::test]
+    async fn can_write_with_larger_chunk_size() {
+        let name = "x".repeat(300);
+        let mut buf = Vec::new();
+        let mut writer = ChunkWriter::new();
+        writer.set_chunk_size(4096);
+        let num_bytes = writer.write(&mut buf, command(&name)).await.expect("write");
+        assert_eq!(num_bytes, 12 + 313);
+    }
+
+    #[tokio::test]
+    async fn can_write_extended_timestamp() {
+        let name = "x".repeat(300);
+        let mut buf = Vec::new();
+        let mut writer = ChunkWriter::new();
+        let mut message = Message::new(
+            None,
+            MessageData::Command(MessageCommand {
+                name,
+                id: 0.0,
+                data: Value::Null,
+                opt: Vec::new(),
+            }),
+        );
+        message.timestamp = 0x0100_0000;
+        let num_bytes = writer
+            .write(&mut buf, Chunk::Msg(message.clone()))
+            .await
+            .expect("write");
+        // every chunk carries the 4 byte extended timestamp
+        assert_eq!(num_bytes, 12 + 4 + 313 + (1 + 4) * 2);
+        assert_eq!(buf[1..4], [0xff, 0xff, 0xff]);
+        assert_eq!(buf[12..16], [0x01, 0x00, 0x00, 0x00]);
+
+        let bytes: &[u8] = &buf;
+        let (chunk, _) = ChunkReader::new().read(bytes).await.expect("read");
+        assert_eq!(chunk, Chunk::Msg(message));
+    }
+
+    #[tokio::test]
+    async fn can_repeat_extended_timestamp_delta() {
+        let mut buf = Vec::new();
+        let mut writer = ChunkWriter::new();
+        let audio = |timestamp| {
+            let mut message = Message::new(Some(1), MessageData::Audio(vec![0xaf, 0x01]));
+            message.timestamp = timestamp;
+            Chunk::Msg(message)
+        };
+        let timestamps = [0, 0x0100_0000, 0x0200_0000];
+        for timestamp in &timestamps {
+            writer
+                .write(&mut buf, audio(*timestamp))
+                .await
+                .expect("write");
+        }
+        // the last is type 3, with the extended delta
+        assert_eq!(
+            buf[buf.len() - 7..buf.len() - 2],
+            [0xc7, 0x01, 0x00, 0x00, 0x00]
+        );
+
+        let mut bytes: &[u8] = &buf;
+        let mut reader = ChunkReader::new();
+        for timestamp in &timestamps {
+            let (chunk, _) = reader.read(&mut bytes).await.expect("read");
+            assert_eq!(chunk, audio(*timestamp));
+        }
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn can_write_multi_byte_basic_headers() {
+        let mut buf = Vec::new();
+        write_basic_header(&mut buf, 0, 64);
+        write_basic_header(&mut buf, 3, 320);
+        assert_eq!(buf, [0x00, 0x00, 0xc1, 0x00, 0x01]);
+    }
+}
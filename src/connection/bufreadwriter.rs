@@ -1,4 +1,3 @@
-use log::{info, warn};
 use std::ops::{Deref, DerefMut};
 use tokio::prelude::*;
 
@@ -35,21 +34,6 @@ where
     pub fn new(buf: T) -> Self {
         Self { buf }
     }
-    /// useful function with error if not all bytes can be written
-    pub async fn write_exact(&mut self, bytes: &[u8]) -> io::Result<usize>
-    where
-        T: AsyncWrite + Unpin,
-    {
-        let bytes_written = self.buf.write(bytes).await.expect("write");
-        if bytes_written == 0 {
-            warn!(target: "bufreadwriter", "connection unexpectedly closed");
-            panic!("connection unexpectedly closed"); // TODO: return real error
-        } else {
-            info!(target: "bufreadwriter", "wrote {} bytes", bytes_written);
-        }
-        self.buf.flush().await.expect("flush after write"); // TODO: needed?
-        return Ok(bytes_written);
-    }
 }
 
 #[cfg(test)]
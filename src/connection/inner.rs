@@ -1,148 +1,188 @@
 use log::{trace, warn};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use url::Url;
 
 use tokio::prelude::*;
 use tokio::sync::mpsc;
+use tokio::time;
 use tokio::{io::BufReader, net::TcpStream};
 
-use crate::chunk::{Chunk, Signal};
-use crate::message::*;
+use crate::chunk::Chunk;
+use crate::error::Error;
+use crate::proto::{Session, SessionEvent};
 
 use super::bufreadwriter::BufReadWriter;
-use super::handshake::{Handshake, HandshakeProcessResult, PeerType};
-
-// private connection owned by read/write thread
-pub struct InnerConnection {
-    rx_to_server: mpsc::Receiver<Message>,
-    cn: BufReadWriter<BufReader<TcpStream>>,
-    window_ack_size: u32,
-    chunk_size: u32,
+use super::with_timeout;
+
+// open a TCP connection to the host and port in the URL, by default
+// port 443 for rtmps and 1935 otherwise
+pub async fn connect_tcp(url: &Url, connect_timeout: Option<Duration>) -> Result<TcpStream, Error> {
+    let host = match url.host() {
+        Some(h) => h,
+        None => return Err(Error::InvalidUrl(format!("no host in {}", url))),
+    };
+
+    let default_port = if url.scheme() == "rtmps" { 443 } else { 1935 };
+    let port = url.port().unwrap_or(default_port);
+    let addr = format!("{}:{}", host, port);
+    let tcp = with_timeout(connect_timeout, "tcp connect", TcpStream::connect(addr)).await??;
+    tcp.set_nodelay(true)?;
+    Ok(tcp)
 }
 
-impl InnerConnection {
-    pub async fn new(url: Url, rx_to_server: mpsc::Receiver<Message>) -> Self {
-        let host = match url.host() {
-            Some(h) => h,
-            None => panic!("host required"),
-        };
+// what Connection hands to the socket task
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)] // almost all are chunks, boxing saves nothing
+pub enum ToServer {
+    Chunk(Chunk),
+    ForgetCommand(u32), // gave up waiting for its response
+}
 
-        let port = url.port().unwrap_or(1935);
-        let addr = format!("{}:{}", host, port);
-        let tcp = TcpStream::connect(addr)
-            .await
-            .expect("tcp connection failed");
-        tcp.set_nodelay(true).expect("set_nodelay call failed");
+// private connection owned by read/write thread, moving bytes between
+// the transport and the protocol session
+pub struct InnerConnection<T: AsyncRead + AsyncWrite + Unpin> {
+    rx_to_server: mpsc::Receiver<ToServer>,
+    cn: BufReadWriter<BufReader<T>>,
+    session: Session,
+    // ping timestamps are milliseconds since the connection started
+    started: Instant,
+    ping_interval: Option<Duration>,
+    rtt: Arc<AtomicU32>, // milliseconds, shared with Connection
+}
 
+impl<T: AsyncRead + AsyncWrite + Unpin> InnerConnection<T> {
+    // handshake over a connected transport
+    pub async fn new(
+        transport: T,
+        rx_to_server: mpsc::Receiver<ToServer>,
+        ping_interval: Option<Duration>,
+        rtt: Arc<AtomicU32>,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Self, Error> {
         let mut cn = InnerConnection {
             rx_to_server,
-            cn: BufReadWriter::new(BufReader::new(tcp)),
-            window_ack_size: 2500000,
-            chunk_size: 1024, // TODO: is this a good default?
+            cn: BufReadWriter::new(BufReader::new(transport)),
+            session: Session::client()?,
+            started: Instant::now(),
+            ping_interval,
+            rtt,
         };
-        cn.connect_handshake().await.unwrap();
-        cn
+        with_timeout(connect_timeout, "handshake", cn.connect_handshake()).await??;
+        Ok(cn)
+    }
+
+    fn timestamp(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
+
+    // write whatever the session has encoded since last time
+    async fn write_output(&mut self) -> Result<(), Error> {
+        let output = self.session.take_output();
+        if !output.is_empty() {
+            trace!(target: "rtmp::Connection", "num bytes: {:?}", output.len());
+            self.cn.write_all(&output).await?;
+            self.cn.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn handle_input(&mut self, bytes: &[u8], context: &str) -> Result<(), Error> {
+        if bytes.is_empty() {
+            let msg = format!("connection closed{}", context);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg).into());
+        }
+        trace!(target: "rtmp::Connection", "bytes read: {}", bytes.len());
+        self.session.handle_input(bytes)
     }
 
-    async fn handle_chunk(
+    // ping responses are handled here, everything else goes to the
+    // message receiver
+    async fn handle_event(
         &mut self,
-        chunk: Chunk,
-        mut tx: mpsc::Sender<Message>,
-    ) -> io::Result<()> {
-        trace!(target: "rtmp::Connection", "handle_chunk {:?}", chunk);
-        match chunk {
-            Chunk::Control(Signal::SetWindowAckSize(size)) => {
-                self.window_ack_size = size;
-                warn!(target: "rtmp::Connection",
-        "SetWindowAckSize - set window_ack_size {:?}", size)
-            }
-            Chunk::Control(Signal::SetPeerBandwidth(size, _limit)) => {
-                self.window_ack_size = size;
-                warn!(target: "rtmp::Connection", "SetPeerBandwidth - set window_ack_size {:?}", size)
-            }
-            Chunk::Control(Signal::SetChunkSize(size)) => {
-                self.chunk_size = size;
-                warn!(target: "rtmp::Connection", "SetChunkSize - set chunk_size {:?}", size)
-            }
-            Chunk::Control(Signal::UserControlMessage(event_type)) => {
-                warn!(target: "rtmp::Connection", "UserControlMessage {:?} - unhandled", event_type)
+        event: SessionEvent,
+        tx: &mut mpsc::Sender<Result<SessionEvent, Error>>,
+    ) -> Result<(), Error> {
+        match event {
+            SessionEvent::HandshakeComplete => {}
+            // our pings are never from the future, so ignore any that are
+            SessionEvent::PingResponse(timestamp) => {
+                match self.timestamp().checked_sub(timestamp) {
+                    Some(rtt) => {
+                        trace!(target: "rtmp::Connection", "PingResponse - rtt {:?}ms", rtt);
+                        self.rtt.store(rtt, Ordering::SeqCst);
+                    }
+                    None => {
+                        warn!(target: "rtmp::Connection", "PingResponse for unsent ping {}", timestamp)
+                    }
+                }
             }
-
-            Chunk::Msg(m) => {
-                trace!(target: "rtmp::Connection", "tx message {:?}", m);
-                tx.send(m)
-                    .await
-                    .expect("transfer message to trigger callback");
+            event => {
+                trace!(target: "rtmp::Connection", "tx event {:?}", event);
+                tx.send(Ok(event)).await?;
             }
-            _ => warn!(target: "rtmp::Connection", "unhandled chunk: {:?}", chunk),
         }
         Ok(())
     }
-    // read messages from TCPStream
-    // - handle protocol control messages
-    // - send RTMP messages via tx
+
+    // read from the transport into the session, sending its events via tx
     // after connecting to server, then handle sending messages
-    // - recv messages (via self.rx_to_server) and write as chunks
-    pub async fn process_message_loop(&mut self, tx: mpsc::Sender<Message>) -> io::Result<()> {
+    // - recv messages (via self.rx_to_server) and hand them to the session
+    pub async fn process_message_loop(
+        &mut self,
+        mut tx: mpsc::Sender<Result<SessionEvent, Error>>,
+    ) -> Result<(), Error> {
         // This note totally belongs somewhere else now, just not sure where!
         // expected connect sequence
         // <---- Window Ack Size from server
         // <---- Set Peer Bandwidth from server
         // ----> Set Peer Bandwidth send to server
+        let ping_enabled = self.ping_interval.is_some();
+        let period = self
+            .ping_interval
+            .unwrap_or_else(|| Duration::from_secs(60));
+        let mut ping_timer = time::interval_at(time::Instant::now() + period, period);
+        let mut read_buffer = [0_u8; 4096];
         loop {
+            self.write_output().await?;
+            while let Some(event) = self.session.poll_event() {
+                self.handle_event(event, &mut tx).await?;
+            }
+            // outgoing messages wait while the peer is behind on acknowledgements
+            let can_send = self.session.can_send();
             tokio::select! {
-                Some(outgoing_msg) = self.rx_to_server.recv()  => {
-                    trace!(target: "rtmp::Connection", "outgoing message: {:?}", outgoing_msg);
-                    // TODO: this shouldn't be synchronous
-                    let mut tmp_buf = Vec::new();
-                    Chunk::write(&mut tmp_buf, Chunk::Msg(outgoing_msg.clone())).await?;
-                    trace!(target: "rtmp::Connection", "outgoing bytes: {:02x?}", tmp_buf);
-                    trace!(target: "rtmp::Connection", "num bytes: {:?}", tmp_buf.len());
-
-                    Chunk::write(&mut self.cn.buf, Chunk::Msg(outgoing_msg)).await?;
+                _ = ping_timer.tick(), if ping_enabled => {
+                    let timestamp = self.timestamp();
+                    self.session.ping(timestamp)?;
+                }
+                Some(outgoing) = self.rx_to_server.recv(), if can_send => {
+                    trace!(target: "rtmp::Connection", "outgoing: {:?}", outgoing);
+                    match outgoing {
+                        ToServer::Chunk(chunk) => self.session.send_chunk(chunk)?,
+                        ToServer::ForgetCommand(id) => self.session.forget_command(id),
+                    }
                 }
-                Ok(response) = Chunk::read(&mut self.cn.buf) => {
-                    // TODO: need to distinguish not enough data from real errors
-                    let (chunk, _num_bytes) = response;
-                    self.handle_chunk(chunk, tx.clone()).await?;
+                num_bytes = self.cn.read(&mut read_buffer) => {
+                    let num_bytes = num_bytes?;
+                    self.handle_input(&read_buffer[..num_bytes], "")?;
                 }
             }
         }
         // unreachable Ok(())
     }
 
-    async fn connect_handshake(&mut self) -> io::Result<()> {
-        let mut handshake = Handshake::new(PeerType::Client);
-        let c0_and_c1 = handshake.generate_outbound_p0_and_p1().unwrap();
-        self.cn.write(&c0_and_c1).await.expect("write c0_and_c1");
-
+    async fn connect_handshake(&mut self) -> Result<(), Error> {
+        // keep reading until we complete the handshake
+        let mut read_buffer = [0_u8; 1024];
         loop {
-            // keep reading until we complete the handshake
-            let mut read_buffer = [0_u8; 1024];
-            let num_bytes = self.cn.read(&mut read_buffer).await.expect("read");
-            if num_bytes == 0 {
-                panic!("connection unexpectedly closed"); // TODO: return real error?
-            }
-            trace!(target: "rtmp::connect", "bytes read: {}", num_bytes);
-            let (is_finished, response_bytes) = match handshake.process_bytes(&read_buffer) {
-                Err(x) => panic!("Error returned: {:?}", x),
-                Ok(HandshakeProcessResult::InProgress {
-                    response_bytes: bytes,
-                }) => (false, bytes),
-                Ok(HandshakeProcessResult::Completed {
-                    response_bytes: bytes,
-                    remaining_bytes: _,
-                }) => (true, bytes),
-            };
-            if response_bytes.len() > 0 {
-                self.cn
-                    .write_exact(&response_bytes)
-                    .await
-                    .expect("handshake");
-            }
-            if is_finished {
+            self.write_output().await?;
+            if self.session.is_handshake_complete() {
                 trace!(target: "rtmp::connect", "handshake completed");
                 return Ok(());
             }
+            let num_bytes = self.cn.read(&mut read_buffer).await?;
+            self.handle_input(&read_buffer[..num_bytes], " during handshake")?;
         }
     }
 }
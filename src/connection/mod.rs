@@ -1,19 +1,30 @@
 use log::{info, trace, warn};
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::runtime::Handle;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tokio::sync::Mutex;
+use tokio::time;
 use url::Url;
 
-use crate::amf::Value;
+use crate::amf::{Value, ValueMap};
+use crate::chunk::{Chunk, Signal};
+use crate::error::Error;
 use crate::message::*;
+use crate::proto::SessionEvent;
 use crate::stream::*;
 
 mod inner;
-use inner::InnerConnection;
+use inner::{connect_tcp, InnerConnection, ToServer};
+
+mod tls;
+use tls::TlsOptions;
 
 // used by inner
 // TODO: seems weird to "pub" when internal to module, but don't know syntax
@@ -23,20 +34,51 @@ pub mod handshake;
 // TODO: maybe this should be configurable?
 const CHANNEL_SIZE: usize = 100;
 
-type CommandsAwaitingResponse =
-    HashMap<u32, oneshot::Sender<Result<MessageResponse, MessageError>>>;
+// rtt value until a ping has been answered
+const NO_RTT: u32 = u32::MAX;
+
+type CommandsAwaitingResponse = HashMap<u32, oneshot::Sender<Result<MessageResponse, Error>>>;
+
+type Streams = HashMap<u32, mpsc::Sender<NetStreamEvent>>;
 
-type Streams = HashMap<u32, mpsc::Sender<MessageStatus>>;
+// anything a connection can run over, e.g. a Unix socket or a proxy
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+// transport given to with_transport, taken by connect
+#[derive(Clone, Default)]
+struct GivenTransport(Arc<std::sync::Mutex<Option<Box<dyn Transport>>>>);
+
+impl GivenTransport {
+    fn take(&self) -> Option<Box<dyn Transport>> {
+        self.0.lock().ok()?.take()
+    }
+}
+
+impl fmt::Debug for GivenTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let given = self.0.lock().is_ok_and(|transport| transport.is_some());
+        write!(f, "GivenTransport({})", given)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Connection {
     url: Url,
     is_connected: Arc<AtomicBool>,
     next_cmd_id: Arc<AtomicU32>,
-    to_server_tx: Option<mpsc::Sender<Message>>, // messages destined server go her
+    to_server_tx: Option<mpsc::Sender<ToServer>>, // messages destined server go her
     // stream_callback: fn(NetStream, Message) -> (),
     commands_awaiting_response: Arc<Mutex<CommandsAwaitingResponse>>,
     streams: Arc<Mutex<Streams>>,
+    ping_interval: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    command_timeout: Option<Duration>,
+    tls: TlsOptions,
+    transport: GivenTransport,
+    rtt: Arc<AtomicU32>,             // milliseconds
+    object_encoding: ObjectEncoding, // requested in connect
+    use_amf3: Arc<AtomicBool>,       // server accepted AMF3
 }
 
 // how to support closure as well as functions?
@@ -58,7 +100,7 @@ pub struct Connection {
 //    |
 
 impl Connection {
-    // todo: new_with_transport, then new can defer creation of connection
+    /// Connects to the host in the URL with TCP, or TLS for `rtmps`.
     pub fn new(url: Url) -> Self {
         info!(target: "rtmp::Connection", "new");
 
@@ -69,6 +111,29 @@ impl Connection {
             to_server_tx: None,
             commands_awaiting_response: Default::default(),
             streams: Default::default(),
+            ping_interval: None,
+            connect_timeout: None,
+            command_timeout: None,
+            tls: Default::default(),
+            transport: Default::default(),
+            rtt: Arc::new(AtomicU32::new(NO_RTT)),
+            object_encoding: ObjectEncoding::Amf0,
+            use_amf3: Default::default(),
+        }
+    }
+
+    /// Runs the connection over a transport that is already connected,
+    /// such as a Unix socket or a stream through a proxy.  The URL is
+    /// still sent to the server in `connect`, its host is not used.  Only
+    /// the first `connect` uses the transport.
+    pub fn with_transport<T>(url: Url, io: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let io: Box<dyn Transport> = Box::new(io);
+        Self {
+            transport: GivenTransport(Arc::new(std::sync::Mutex::new(Some(io)))),
+            ..Self::new(url)
         }
     }
 
@@ -77,7 +142,68 @@ impl Connection {
     }
 
     pub fn set_connected(&self, connected: bool) {
-        self.is_connected.fetch_or(connected, Ordering::SeqCst);
+        self.is_connected.store(connected, Ordering::SeqCst);
+    }
+
+    /// Sends a PingRequest to the server at this interval once connected,
+    /// keeping `rtt` up to date.  Takes effect on the next `connect`.
+    pub fn set_ping_interval(&mut self, interval: Duration) {
+        self.ping_interval = Some(interval);
+    }
+
+    /// Limits each step of `connect`: the TCP connection, the handshake
+    /// and waiting for the response to the connect command.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = Some(timeout);
+    }
+
+    /// Limits how long `send_command` and `send_raw_command` wait for
+    /// the server to respond.
+    pub fn set_command_timeout(&mut self, timeout: Duration) {
+        self.command_timeout = Some(timeout);
+    }
+
+    /// Sets both the connect and the command timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.set_connect_timeout(timeout);
+        self.set_command_timeout(timeout);
+    }
+
+    /// Trusts this certificate authority for `rtmps` connections, in
+    /// addition to the system's root certificates.
+    pub fn add_root_certificate(&mut self, cert: native_tls::Certificate) {
+        self.tls.root_certificates.push(cert);
+    }
+
+    /// Accepts any certificate for `rtmps` connections, including
+    /// self-signed and expired ones.  Only for local testing: this lets
+    /// anyone intercept the connection.
+    pub fn danger_accept_invalid_certs(&mut self, accept: bool) {
+        self.tls.accept_invalid_certs = accept;
+    }
+
+    /// Round-trip time measured by the most recent ping the server
+    /// answered, or None if no ping has been answered yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::SeqCst) {
+            NO_RTT => None,
+            ms => Some(Duration::from_millis(ms.into())),
+        }
+    }
+
+    /// Asks the server for this object encoding in connect.  Commands and
+    /// data use AMF3 messages only if the server agrees in its response.
+    pub fn set_object_encoding(&mut self, encoding: ObjectEncoding) {
+        self.object_encoding = encoding;
+    }
+
+    // encoding agreed with the server, AMF0 until connected
+    fn encoding(&self) -> ObjectEncoding {
+        if self.use_amf3.load(Ordering::SeqCst) {
+            ObjectEncoding::Amf3
+        } else {
+            ObjectEncoding::Amf0
+        }
     }
 
     // get_next_cmd_id generates a unique Command transaction id
@@ -93,7 +219,7 @@ impl Connection {
         &mut self,
         name: &str,
         params: Vec<Value>,
-    ) -> Result<MessageResponse, MessageError> {
+    ) -> Result<MessageResponse, Error> {
         self.send_raw_command(CONNECTION_CHANNEL, name, GENERATE, Value::Null, params)
             .await
     }
@@ -105,15 +231,30 @@ impl Connection {
         transaction_id: Option<u32>, // if not given, generate one
         data: Value,
         opt: Vec<Value>,
-    ) -> Result<MessageResponse, MessageError> {
+    ) -> Result<MessageResponse, Error> {
         let cmd_id = transaction_id.unwrap_or_else(|| self.get_next_cmd_id());
+        let timeout = self.command_timeout;
+        self.request(stream_id, name, cmd_id, data, opt, timeout)
+            .await
+    }
+
+    // send a command and wait for the response with this transaction id
+    async fn request(
+        &mut self,
+        stream_id: Option<u32>,
+        name: &str,
+        cmd_id: u32,
+        data: Value,
+        opt: Vec<Value>,
+        timeout: Option<Duration>,
+    ) -> Result<MessageResponse, Error> {
         let mut to_server_tx = match &self.to_server_tx {
             Some(tx) => tx.clone(),
-            None => panic!("need to be connected"),
+            None => return Err(Error::NotConnected),
         };
 
         let id: f64 = cmd_id.into();
-        let msg = Message::new(
+        let mut msg = Message::new(
             stream_id,
             MessageData::Command(MessageCommand {
                 name: name.to_string(),
@@ -122,8 +263,10 @@ impl Connection {
                 opt,
             }),
         );
-        to_server_tx.send(msg).await?;
+        msg.encoding = self.encoding();
 
+        // register before sending, so a response or a socket error that
+        // arrives right away still finds us
         let (sender, receiver) = oneshot::channel();
         let existing_subscriber = self
             .commands_awaiting_response
@@ -133,29 +276,46 @@ impl Connection {
         if existing_subscriber.is_some() {
             warn!("ignoring unexpected response for {:?}", existing_subscriber);
         }
-        receiver.await?
+        if let Err(err) = to_server_tx.send(ToServer::Chunk(Chunk::Msg(msg))).await {
+            self.commands_awaiting_response.lock().await.remove(&cmd_id);
+            return Err(err.into());
+        }
+        match with_timeout(timeout, name, receiver).await {
+            Ok(response) => response?,
+            Err(err) => {
+                // the session drops a response that arrives later
+                self.commands_awaiting_response.lock().await.remove(&cmd_id);
+                let _ = to_server_tx.send(ToServer::ForgetCommand(cmd_id)).await;
+                Err(err)
+            }
+        }
     }
 
     // connect must have transaction ID #1
     async fn send_connect_command(
         &mut self,
         // params: Vec<Value>, TODO: check API, caller can send some kind of server params, not sure where they go
-    ) -> Result<MessageResponse, MessageError> {
-        let mut properties = HashMap::new();
-        let mut url_path = self.url.path_segments().unwrap();
-        let app_name = url_path.next().unwrap();
+    ) -> Result<MessageResponse, Error> {
+        let mut properties = ValueMap::new();
+        let app_name = match self.url.path_segments().and_then(|mut path| path.next()) {
+            Some(name) => name,
+            None => return Err(Error::InvalidUrl(format!("no app name in {}", self.url))),
+        };
         properties.insert("app".to_string(), Value::Utf8(app_name.to_string()));
         let flash_version = "MAC 10,0,32,18".to_string(); // TODO: must we, really?
         properties.insert("flashVer".to_string(), Value::Utf8(flash_version));
-        // properties.insert("objectEncoding".to_string(), Amf0Value::Number(0.0));
+        let encoding = f64::from(self.object_encoding as u8);
+        properties.insert("objectEncoding".to_string(), Value::Number(encoding));
         properties.insert("tcUrl".to_string(), Value::Utf8(self.url.to_string()));
 
-        self.send_raw_command(
+        let timeout = self.connect_timeout;
+        self.request(
             CONNECTION_CHANNEL,
             "connect",
-            Some(1),
+            1,
             Value::Object(properties),
             Vec::new(),
+            timeout,
         )
         .await
     }
@@ -166,12 +326,7 @@ impl Connection {
         stream_id: u32,
         name: &str,
         params: Vec<Value>,
-    ) -> Result<(), MessageError> {
-        let mut to_server_tx = match &self.to_server_tx {
-            Some(tx) => tx.clone(),
-            None => panic!("need to be connected"),
-        };
-
+    ) -> Result<(), Error> {
         let msg = Message::new(
             Some(stream_id),
             MessageData::Command(MessageCommand {
@@ -181,11 +336,46 @@ impl Connection {
                 opt: params,
             }),
         );
-        to_server_tx.send(msg).await?;
+        self.send_message(msg).await
+    }
+
+    // queue a message for the server, no response is expected
+    pub(crate) async fn send_message(&mut self, mut msg: Message) -> Result<(), Error> {
+        msg.encoding = self.encoding();
+        let mut to_server_tx = match &self.to_server_tx {
+            Some(tx) => tx.clone(),
+            None => return Err(Error::NotConnected),
+        };
+        to_server_tx.send(ToServer::Chunk(Chunk::Msg(msg))).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn send_signal(&mut self, signal: Signal) -> Result<(), Error> {
+        let mut to_server_tx = match &self.to_server_tx {
+            Some(tx) => tx.clone(),
+            None => return Err(Error::NotConnected),
+        };
+        to_server_tx
+            .send(ToServer::Chunk(Chunk::Control(signal)))
+            .await?;
         Ok(())
     }
 
-    pub async fn new_stream(&mut self) -> Result<(NetStream, MessageResponse), MessageError> {
+    /// Sets the maximum payload size of chunks sent to the server, for
+    /// example raising it from the default of 128 bytes to 4096 before
+    /// publishing.  Messages queued after this call are split using the
+    /// new size.
+    pub async fn set_chunk_size(&mut self, size: u32) -> Result<(), Error> {
+        self.send_signal(Signal::SetChunkSize(size)).await
+    }
+
+    /// Tells the server how many bytes it may send before it should
+    /// expect an acknowledgement from us.
+    pub async fn set_window_ack_size(&mut self, size: u32) -> Result<(), Error> {
+        self.send_signal(Signal::SetWindowAckSize(size)).await
+    }
+
+    pub async fn new_stream(&mut self) -> Result<(NetStream, MessageResponse), Error> {
         let msg = self.send_command("createStream", Vec::new()).await?;
         match msg {
             MessageResponse {
@@ -196,14 +386,13 @@ impl Connection {
                 let stream = NetStream::new(id, self.clone()).await;
                 Ok((stream, msg))
             }
-            MessageResponse { opt: _, .. } => Err(MessageError::new_status(
-                "NetStream.Create.Failed", // TODO: check consistency
-                "Server did not provide a stream id number",
+            MessageResponse { opt: _, .. } => Err(Error::Protocol(
+                "server did not provide a stream id number".to_string(),
             )),
         }
     }
 
-    pub(crate) async fn add_stream(&self, id: u32) -> mpsc::Receiver<MessageStatus> {
+    pub(crate) async fn add_stream(&self, id: u32) -> mpsc::Receiver<NetStreamEvent> {
         let (sender, receiver) = mpsc::channel(100);
         self.streams.lock().await.insert(id, sender);
         receiver
@@ -213,71 +402,165 @@ impl Connection {
         self.streams.lock().await.remove(&id);
     }
 
+    // deliver to the NetStream with this id, if it is still around
+    async fn notify_stream(&self, stream_id: u32, event: NetStreamEvent) {
+        if let Some(sender) = self.streams.lock().await.get_mut(&stream_id) {
+            trace!(target: "rtmp:message_receiver", "sending...");
+            if sender.send(event).await.is_err() {
+                warn!("Stream Receiver for stream id #{} went away", stream_id);
+            } else {
+                trace!(target: "rtmp:message_receiver", "sent!");
+            }
+        } else {
+            warn!("Got {:?} on stream {} with no receiver!", event, stream_id);
+        }
+    }
+
     //     to_server_rx: ownership moves to the spawned thread, its job is to
     //                  recv messages on this channel and send 'em to the server
     //  from_server_tx: the thread also listens on the socket, reads messages
+    //                  and sends them on this channel, then the error that
+    //                  closed the connection
     //                  and sends them on this channel
+    //           ready: gets the result of the TCP connection and handshake
     fn spawn_socket_process_loop(
         &mut self,
-        to_server_rx: mpsc::Receiver<Message>,
-        from_server_tx: mpsc::Sender<Message>,
+        to_server_rx: mpsc::Receiver<ToServer>,
+        from_server_tx: mpsc::Sender<Result<SessionEvent, Error>>,
+        ready: oneshot::Sender<Result<(), Error>>,
     ) {
         let url = self.url.clone();
+        let connect_timeout = self.connect_timeout;
+        let transport = self.transport.take();
+        let connection = self.clone();
         let runtime = Handle::current();
         let _cn_handle = runtime.spawn(async move {
+            if let Some(transport) = transport {
+                connection
+                    .run_socket(transport, to_server_rx, from_server_tx, ready)
+                    .await;
+                return;
+            }
             trace!(target: "rtmp:spawn_socket_process_loop", "creating socket connection");
-            // maybe InnerConnection is chunkstream?
-            let mut cn = InnerConnection::new(url, to_server_rx).await;
-
-            trace!(target: "rtmp:spawn_socket_process_loop", "chunkstream connected");
-            cn.process_message_loop(from_server_tx)
-                .await
-                .expect("read until socket closes");
+            let tcp = match connect_tcp(&url, connect_timeout).await {
+                Ok(tcp) => tcp,
+                Err(err) => {
+                    let _ = ready.send(Err(err));
+                    return;
+                }
+            };
+            if url.scheme() != "rtmps" {
+                connection
+                    .run_socket(tcp, to_server_rx, from_server_tx, ready)
+                    .await;
+                return;
+            }
+            let host = url.host_str().unwrap_or_default();
+            let tls = connection.tls.connect(host, tcp);
+            match with_timeout(connect_timeout, "tls handshake", tls).await {
+                Ok(Ok(stream)) => {
+                    connection
+                        .run_socket(stream, to_server_rx, from_server_tx, ready)
+                        .await
+                }
+                Ok(Err(err)) | Err(err) => {
+                    let _ = ready.send(Err(err));
+                }
+            }
         });
     }
 
-    pub fn spawn_message_receiver(&mut self, mut from_server_rx: mpsc::Receiver<Message>) {
+    // handshake over the transport, then read and write chunks until the
+    // connection closes
+    async fn run_socket<T>(
+        self,
+        transport: T,
+        to_server_rx: mpsc::Receiver<ToServer>,
+        mut from_server_tx: mpsc::Sender<Result<SessionEvent, Error>>,
+        ready: oneshot::Sender<Result<(), Error>>,
+    ) where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        // maybe InnerConnection is chunkstream?
+        let cn = InnerConnection::new(
+            transport,
+            to_server_rx,
+            self.ping_interval,
+            self.rtt.clone(),
+            self.connect_timeout,
+        );
+        let mut cn = match cn.await {
+            Ok(cn) => cn,
+            Err(err) => {
+                let _ = ready.send(Err(err));
+                return;
+            }
+        };
+        let _ = ready.send(Ok(()));
+
+        trace!(target: "rtmp:spawn_socket_process_loop", "chunkstream connected");
+        let err = match cn.process_message_loop(from_server_tx.clone()).await {
+            Ok(()) => Error::NotConnected,
+            Err(err) => err,
+        };
+        warn!(target: "rtmp:spawn_socket_process_loop", "connection closed: {}", err);
+        // no more commands can be queued once the receiver is gone
+        drop(cn);
+        // the message receiver closes the connection after handling what
+        // was read before the error, such as an '_error' for a command
+        if let Err(mpsc::error::SendError(Err(err))) = from_server_tx.send(Err(err)).await {
+            self.close(err).await;
+        }
+    }
+
+    // fail every command still waiting for a response with the error
+    // that closed the connection
+    async fn close(&self, err: Error) {
+        self.set_connected(false);
+        let err = Arc::new(err);
+        for (_, sender) in self.commands_awaiting_response.lock().await.drain() {
+            let _ = sender.send(Err(Error::Closed(err.clone())));
+        }
+    }
+
+    pub(crate) fn spawn_message_receiver(
+        &mut self,
+        mut from_server_rx: mpsc::Receiver<Result<SessionEvent, Error>>,
+    ) {
         let runtime = Handle::current();
         let connection = self.clone();
         let _res_handle = runtime.spawn(async move {
             trace!(target: "rtmp:message_receiver", "spawn recv handler");
             let mut num: i32 = 1; // just for debugging
             loop {
-                let msg = from_server_rx.recv().await.expect("recv from server");
-                trace!(target: "rtmp:message_receiver", "#{}) recv from server {:?}", num, msg);
-                match msg {
-                    Message { data: MessageData::Response(response), .. } => {
-                    let cmd_id = response.id as u32;
-
-                        if let Some(sender) = connection.commands_awaiting_response.lock().await.remove(&cmd_id) {
-                            if sender.send(Ok(response)).is_err() {
-                                warn!("Receiver for cmd {} went away", cmd_id);
+                let event = match from_server_rx.recv().await {
+                    Some(Ok(event)) => event,
+                    Some(Err(err)) => {
+                        connection.close(err).await;
+                        break;
+                    }
+                    None => break, // socket task has exited
+                };
+                trace!(target: "rtmp:message_receiver", "#{}) recv from server {:?}", num, event);
+                match event {
+                    SessionEvent::Response { id, result } => {
+                        if let Some(sender) = connection.commands_awaiting_response.lock().await.remove(&id) {
+                            if sender.send(result.map_err(Error::from)).is_err() {
+                                warn!("Receiver for cmd {} went away", id);
                             }
                         } else {
-                            warn!("Got a response for unregistered command {}", cmd_id);
-                        }
-                    },
-                    Message { stream_id, data: MessageData::Status(status) } => {
-                        trace!(target: "rtmp:message_receiver", "status: {:?}", status);
-                        if stream_id == 0 {
-                            panic!("unexpected status with stream id 0");   // TODO: I think this means it is a NetConnection status message
-                        } else {
-                            if let Some(sender) = connection.streams.lock().await.get_mut(&stream_id) {
-                                trace!(target: "rtmp:message_receiver", "sending...");
-
-                                if sender.send(status).await.is_err() {
-                                    warn!("Stream Receiver for stream id #{} went away", stream_id);
-                                } else {
-                                    trace!(target: "rtmp:message_receiver", "sent!");
-                                }
-                            } else {
-                                warn!("Got a status on stream {} with no receiver!", stream_id);
-                            }
+                            warn!("Got a response for unregistered command {}", id);
                         }
-
-                    },
-                    _ => {
-                        warn!(target: "rtmp:connect_with_callback", "unhandled message from server {:?}", msg)
+                    }
+                    // the server begins stream 0 for the NetConnection itself
+                    SessionEvent::Stream { stream_id: 0, event } => {
+                        trace!(target: "rtmp:message_receiver", "{:?}", event)
+                    }
+                    SessionEvent::Stream { stream_id, event } => {
+                        connection.notify_stream(stream_id, event).await
+                    }
+                    event => {
+                        warn!(target: "rtmp:connect_with_callback", "unhandled message from server {:?}", event)
                     }
                 }
                 num += 1;
@@ -285,17 +568,20 @@ impl Connection {
         });
     }
 
-    pub async fn connect(&mut self) -> Result<MessageResponse, MessageError> {
-        let (from_server_tx, from_server_rx) = mpsc::channel::<Message>(CHANNEL_SIZE);
+    pub async fn connect(&mut self) -> Result<MessageResponse, Error> {
+        let (from_server_tx, from_server_rx) =
+            mpsc::channel::<Result<SessionEvent, Error>>(CHANNEL_SIZE);
 
-        let (to_server_tx, to_server_rx) = mpsc::channel::<Message>(CHANNEL_SIZE);
+        let (to_server_tx, to_server_rx) = mpsc::channel::<ToServer>(CHANNEL_SIZE);
 
-        self.to_server_tx = Some(to_server_tx); // Connection methods use this to send messages to server
+        let (ready_tx, ready_rx) = oneshot::channel();
+        self.spawn_socket_process_loop(to_server_rx, from_server_tx, ready_tx);
+        // DNS, TCP and handshake failures end up here
+        ready_rx.await??;
 
-        self.spawn_socket_process_loop(to_server_rx, from_server_tx);
+        self.to_server_tx = Some(to_server_tx); // Connection methods use this to send messages to server
         self.spawn_message_receiver(from_server_rx);
-        // queue the connect command, then await ...
-        // - handshake completion
+        // send the connect command, then await ...
         // - queued command gets sent in process_message_loop
         // - receive connect response
         let response = self.send_connect_command().await;
@@ -305,6 +591,8 @@ impl Connection {
                 if status.code == "NetConnection.Connect.Success" {
                     trace!(target: "rtmp::Connection", "setting is_connected: {}", true);
                     self.set_connected(true);
+                    let amf3 = self.object_encoding == ObjectEncoding::Amf3 && status.encoding == 3;
+                    self.use_amf3.store(amf3, Ordering::SeqCst);
                 } else {
                     self.set_connected(false);
                 }
@@ -317,26 +605,289 @@ impl Connection {
     pub fn connect_with_callback(
         &mut self,
         f: impl Fn(Connection, Message) -> () + Send + 'static,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Error> {
         trace!(target: "rtmp:connect_with_callback", "url: {}", self.url);
 
         // just block for now, while refactoring
         futures::executor::block_on(async move {
-            let response = self.connect().await;
-            // TODO: need to figure out client error handling expectations
-            if let Ok(msg) = response {
-                trace!(target: "rtmp:connect_with_callback", "connected: {}, {:#?}", self.is_connected(), msg);
-                // want: Message::from_data(msg)
-                f(
-                    self.clone(),
-                    Message {
-                        stream_id: 0,
-                        data: MessageData::Response(msg.clone()),
-                    },
-                );
+            let msg = self.connect().await?;
+            trace!(target: "rtmp:connect_with_callback", "connected: {}, {:#?}", self.is_connected(), msg);
+            // want: Message::from_data(msg)
+            f(
+                self.clone(),
+                Message {
+                    stream_id: 0,
+                    timestamp: 0,
+                    encoding: self.encoding(),
+                    data: MessageData::Response(msg),
+                },
+            );
+            Ok(())
+        })
+    }
+}
+
+// await the future, giving up with a timeout error naming `what` if it
+// takes longer than the timeout
+pub(crate) async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    what: &str,
+    future: F,
+) -> Result<F::Output, Error> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, future)
+            .await
+            .map_err(|_| Error::Timeout(what.to_string())),
+        None => Ok(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkReader, ChunkWriter, Event};
+    use tokio::net::{TcpListener, TcpStream, UnixStream};
+    use tokio::prelude::*;
+
+    fn local_url(port: u16) -> Url {
+        Url::parse(&format!("rtmp://127.0.0.1:{}/live", port)).expect("url")
+    }
+
+    #[tokio::test]
+    async fn connect_returns_tcp_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        drop(listener);
+
+        let result = Connection::new(local_url(port)).connect().await;
+        assert!(matches!(result, Err(Error::Network(..))));
+    }
+
+    #[tokio::test]
+    async fn connect_returns_error_when_closed_during_handshake() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.expect("accept");
+            drop(socket);
+        });
+
+        let result = Connection::new(local_url(port)).connect().await;
+        assert!(matches!(result, Err(Error::Network(..))));
+    }
+
+    #[tokio::test]
+    async fn connect_returns_handshake_error() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("accept");
+            // RTMP version must be 3
+            socket.write_all(&[6; 1537]).await.expect("write");
+            let mut buf = [0; 1537];
+            let _ = socket.read_exact(&mut buf).await;
+        });
+
+        let result = Connection::new(local_url(port)).connect().await;
+        assert!(matches!(result, Err(Error::Handshake(..))));
+    }
+
+    // accept one client and complete the server side of the handshake
+    async fn accept_and_handshake(mut listener: TcpListener) -> TcpStream {
+        let (mut socket, _) = listener.accept().await.expect("accept");
+        server_handshake(&mut socket).await;
+        socket
+    }
+
+    async fn server_handshake<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S) {
+        use handshake::{Handshake, HandshakeProcessResult, PeerType};
+        let mut c0_and_c1 = [0; 1537];
+        socket.read_exact(&mut c0_and_c1).await.expect("c0 and c1");
+        let mut server = Handshake::new(PeerType::Server);
+        if let Ok(HandshakeProcessResult::InProgress { response_bytes }) =
+            server.process_bytes(&c0_and_c1)
+        {
+            socket
+                .write_all(&response_bytes)
+                .await
+                .expect("s0, s1 and s2");
+        }
+        let mut c2 = [0; 1536];
+        socket.read_exact(&mut c2).await.expect("c2");
+    }
+
+    // a command named _result is encoded just like a response
+    async fn send_connect_success<S: AsyncWrite + Unpin>(socket: &mut S) {
+        let mut status = ValueMap::new();
+        status.insert("level".to_string(), Value::Utf8("status".to_string()));
+        status.insert(
+            "code".to_string(),
+            Value::Utf8("NetConnection.Connect.Success".to_string()),
+        );
+        let result = Message::new(
+            None,
+            MessageData::Command(MessageCommand {
+                name: "_result".to_string(),
+                id: 1.0,
+                data: Value::Null,
+                opt: vec![Value::Object(status)],
+            }),
+        );
+        let mut buf = Vec::new();
+        ChunkWriter::new()
+            .write(&mut buf, Chunk::Msg(result))
+            .await
+            .expect("write");
+        socket.write_all(&buf).await.expect("send result");
+    }
+
+    // read until the client goes away, without answering
+    async fn ignore_client<S: AsyncRead + Unpin>(mut socket: S) {
+        let mut buf = [0; 1024];
+        while let Ok(n) = socket.read(&mut buf).await {
+            if n == 0 {
+                break;
             }
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_command_gets_error_that_closed_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        tokio::spawn(async move {
+            let mut socket = accept_and_handshake(listener).await;
+            // close without answering connect
+            let mut buf = [0; 128];
+            let _ = socket.read(&mut buf).await;
         });
 
-        Ok(())
+        let result = Connection::new(local_url(port)).connect().await;
+        match result {
+            Err(Error::Closed(err)) => assert!(matches!(*err, Error::Network(..))),
+            _ => panic!("expected connection closed, got {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_times_out_without_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        tokio::spawn(async move {
+            ignore_client(accept_and_handshake(listener).await).await;
+        });
+
+        let mut cn = Connection::new(local_url(port));
+        cn.set_connect_timeout(Duration::from_millis(50));
+        let result = cn.connect().await;
+        assert!(matches!(result, Err(Error::Timeout(..))));
+        assert!(cn.commands_awaiting_response.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn command_times_out_without_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        tokio::spawn(async move {
+            let mut socket = accept_and_handshake(listener).await;
+            send_connect_success(&mut socket).await;
+            ignore_client(socket).await;
+        });
+
+        let mut cn = Connection::new(local_url(port));
+        cn.set_command_timeout(Duration::from_millis(50));
+        cn.connect().await.expect("connect");
+        let result = cn.send_command("createStream", Vec::new()).await;
+        assert!(matches!(result, Err(Error::Timeout(..))));
+        assert!(cn.commands_awaiting_response.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rtmps_requires_tls_server() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("accept");
+            // answer the TLS client hello with an RTMP handshake
+            let _ = socket.write_all(&[3; 1537]).await;
+            ignore_client(socket).await;
+        });
+
+        let url = Url::parse(&format!("rtmps://127.0.0.1:{}/live", port)).expect("url");
+        let mut cn = Connection::new(url);
+        cn.danger_accept_invalid_certs(true);
+        let result = cn.connect().await;
+        assert!(matches!(result, Err(Error::Tls(..))));
+    }
+
+    #[tokio::test]
+    async fn can_connect_over_given_transport() {
+        let (client, mut server) = UnixStream::pair().expect("pair");
+        tokio::spawn(async move {
+            server_handshake(&mut server).await;
+            send_connect_success(&mut server).await;
+            ignore_client(server).await;
+        });
+
+        // nothing listens on this port, the given transport is used instead
+        let mut cn = Connection::with_transport(local_url(1), client);
+        cn.connect().await.expect("connect");
+        assert!(cn.is_connected());
+    }
+
+    #[tokio::test]
+    async fn send_command_requires_connection() {
+        let mut cn = Connection::new(local_url(1935));
+        let result = cn.send_command("createStream", Vec::new()).await;
+        assert!(matches!(result, Err(Error::NotConnected)));
+    }
+
+    #[tokio::test]
+    async fn ping_response_sets_rtt() {
+        let (client, mut server) = UnixStream::pair().expect("pair");
+        tokio::spawn(async move {
+            server_handshake(&mut server).await;
+            send_connect_success(&mut server).await;
+            // answer the first ping as if it were sent in the future
+            let mut reader = ChunkReader::new();
+            let mut writer = ChunkWriter::new();
+            let mut pings = 0;
+            while let Ok((chunk, _)) = reader.read(&mut server).await {
+                let timestamp = match chunk {
+                    Chunk::Control(Signal::UserControlMessage(Event::PingRequest(t))) => t,
+                    _ => continue,
+                };
+                pings += 1;
+                let timestamp = if pings == 1 {
+                    timestamp + 1_000_000
+                } else {
+                    timestamp
+                };
+                let response = Signal::UserControlMessage(Event::PingResponse(timestamp));
+                let mut buf = Vec::new();
+                writer
+                    .write(&mut buf, Chunk::Control(response))
+                    .await
+                    .expect("write");
+                if server.write_all(&buf).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut cn = Connection::with_transport(local_url(1), client);
+        cn.set_ping_interval(Duration::from_millis(20));
+        cn.connect().await.expect("connect");
+        assert_eq!(cn.rtt(), None);
+        let mut rtt = None;
+        for _ in 0..100 {
+            rtt = cn.rtt();
+            if rtt.is_some() {
+                break;
+            }
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        let rtt = rtt.expect("rtt once a ping is answered");
+        assert!(rtt < Duration::from_secs(1), "rtt {:?}", rtt);
     }
 }
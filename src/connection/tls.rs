@@ -0,0 +1,36 @@
+use native_tls::Certificate;
+use std::fmt;
+use tokio::net::TcpStream;
+use tokio_tls::TlsStream;
+
+use crate::error::Error;
+
+// TLS settings for rtmps:// connections
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    pub root_certificates: Vec<Certificate>,
+    pub accept_invalid_certs: bool,
+}
+
+// Certificate doesn't implement Debug
+impl fmt::Debug for TlsOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsOptions")
+            .field("root_certificates", &self.root_certificates.len())
+            .field("accept_invalid_certs", &self.accept_invalid_certs)
+            .finish()
+    }
+}
+
+impl TlsOptions {
+    // the host name is sent for SNI and checked against the server certificate
+    pub async fn connect(&self, host: &str, tcp: TcpStream) -> Result<TlsStream<TcpStream>, Error> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for cert in &self.root_certificates {
+            builder.add_root_certificate(cert.clone());
+        }
+        builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+        let connector = tokio_tls::TlsConnector::from(builder.build()?);
+        Ok(connector.connect(host, tcp).await?)
+    }
+}
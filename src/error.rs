@@ -1,15 +1,42 @@
+use std::sync::Arc;
 use std::{fmt, io};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::oneshot::error::RecvError;
+
+use crate::amf;
+pub use crate::connection::handshake::{HandshakeError, HandshakeErrorKind};
+use crate::message::MessageError;
+
+/// Errors returned by this crate
 #[derive(Debug)]
 pub enum Error {
-    SampleError,
     Network(io::Error),
+    Amf(amf::Error),           // malformed AMF in a command or data message
+    Protocol(String),          // peer sent something RTMP does not allow
+    Unsupported(String),       // valid RTMP this crate does not handle yet
+    Handshake(HandshakeError), // peer did not complete a valid handshake
+    Tls(native_tls::Error),    // e.g. certificate not trusted
+    Command(MessageError),     // server answered a command with an error
+    InvalidUrl(String),        // e.g. no application name in the path
+    NotConnected,              // not connected yet, or connection closed
+    Timeout(String),           // what we gave up waiting for
+    Closed(Arc<Error>),        // connection closed by this error, shared by pending commands
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), std::fmt::Error> {
         match self {
-            Error::SampleError => write!(f, "Some error string"),
             Error::Network(err) => write!(f, "Network error: {}", err),
+            Error::Amf(err) => write!(f, "AMF error: {}", err),
+            Error::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            Error::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
+            Error::Handshake(err) => write!(f, "Handshake failed: {}", err),
+            Error::Tls(err) => write!(f, "TLS error: {}", err),
+            Error::Command(err) => write!(f, "{}", err),
+            Error::InvalidUrl(msg) => write!(f, "Invalid URL: {}", msg),
+            Error::NotConnected => write!(f, "Not connected"),
+            Error::Timeout(what) => write!(f, "Timed out waiting for {}", what),
+            Error::Closed(err) => write!(f, "Connection closed: {}", err),
         }
     }
 }
@@ -21,3 +48,43 @@ impl From<io::Error> for Error {
         Self::Network(err)
     }
 }
+
+impl From<amf::Error> for Error {
+    fn from(err: amf::Error) -> Self {
+        Self::Amf(err)
+    }
+}
+
+impl From<HandshakeError> for Error {
+    fn from(err: HandshakeError) -> Self {
+        match err.kind {
+            HandshakeErrorKind::Io(err) => Self::Network(err),
+            _ => Self::Handshake(err),
+        }
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(err: native_tls::Error) -> Self {
+        Self::Tls(err)
+    }
+}
+
+impl From<MessageError> for Error {
+    fn from(err: MessageError) -> Self {
+        Self::Command(err)
+    }
+}
+
+// the socket task drops its channels when the connection closes
+impl<T> From<SendError<T>> for Error {
+    fn from(_: SendError<T>) -> Self {
+        Self::NotConnected
+    }
+}
+
+impl From<RecvError> for Error {
+    fn from(_: RecvError) -> Self {
+        Self::NotConnected
+    }
+}
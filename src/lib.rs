@@ -1,10 +1,11 @@
 #![warn(missing_debug_implementations, missing_copy_implementations)]
 
-// used in amf.rs
+// used in amf/mod.rs
 #[macro_use]
 extern crate enum_primitive_derive;
 
 pub mod amf;
+pub mod amf3;
 pub mod error;
 
 mod message;
@@ -12,10 +13,13 @@ pub use message::*;
 
 mod stream;
 pub use stream::NetStream;
+pub use stream::NetStreamEvent;
 pub use stream::RecordFlag;
 
 mod connection;
 pub use connection::Connection;
 
 mod chunk;
+pub mod proto;
+pub mod server;
 mod util;
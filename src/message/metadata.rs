@@ -0,0 +1,159 @@
+use crate::amf::{Value, ValueMap};
+
+/// Stream properties sent by publishers with `@setDataFrame` and received
+/// by players as `onMetaData`.  Properties without a field here are kept
+/// in `extra`, so converting back to a `Value` loses nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub duration: Option<f64>, // seconds, 0 for live streams
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    pub videodatarate: Option<f64>, // kbps
+    pub framerate: Option<f64>,
+    pub videocodecid: Option<f64>,  // FLV codec id, e.g. 7 for AVC
+    pub audiodatarate: Option<f64>, // kbps
+    pub audiosamplerate: Option<f64>,
+    pub audiosamplesize: Option<f64>,
+    pub stereo: Option<bool>,
+    pub audiocodecid: Option<f64>, // FLV sound format, e.g. 10 for AAC
+    pub filesize: Option<f64>,
+    pub encoder: Option<String>,
+    pub extra: ValueMap,
+}
+
+fn take_number(h: &mut ValueMap, key: &str) -> Option<f64> {
+    match h.shift_remove(key) {
+        Some(Value::Number(n)) => Some(n),
+        Some(other) => {
+            h.insert(key.to_string(), other);
+            None
+        }
+        None => None,
+    }
+}
+
+fn take_bool(h: &mut ValueMap, key: &str) -> Option<bool> {
+    match h.shift_remove(key) {
+        Some(Value::Boolean(b)) => Some(b),
+        Some(other) => {
+            h.insert(key.to_string(), other);
+            None
+        }
+        None => None,
+    }
+}
+
+fn take_string(h: &mut ValueMap, key: &str) -> Option<String> {
+    match h.shift_remove(key) {
+        Some(Value::Utf8(s)) => Some(s),
+        Some(other) => {
+            h.insert(key.to_string(), other);
+            None
+        }
+        None => None,
+    }
+}
+
+impl Metadata {
+    /// returns None unless the value is an object or ECMA array
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Object(h) | Value::EcmaArray(h) => {
+                let mut h = h.clone();
+                Some(Self {
+                    duration: take_number(&mut h, "duration"),
+                    width: take_number(&mut h, "width"),
+                    height: take_number(&mut h, "height"),
+                    videodatarate: take_number(&mut h, "videodatarate"),
+                    framerate: take_number(&mut h, "framerate"),
+                    videocodecid: take_number(&mut h, "videocodecid"),
+                    audiodatarate: take_number(&mut h, "audiodatarate"),
+                    audiosamplerate: take_number(&mut h, "audiosamplerate"),
+                    audiosamplesize: take_number(&mut h, "audiosamplesize"),
+                    stereo: take_bool(&mut h, "stereo"),
+                    audiocodecid: take_number(&mut h, "audiocodecid"),
+                    filesize: take_number(&mut h, "filesize"),
+                    encoder: take_string(&mut h, "encoder"),
+                    extra: h,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<Metadata> for Value {
+    fn from(metadata: Metadata) -> Self {
+        let mut h = ValueMap::new();
+        let numbers = vec![
+            ("duration", metadata.duration),
+            ("width", metadata.width),
+            ("height", metadata.height),
+            ("videodatarate", metadata.videodatarate),
+            ("framerate", metadata.framerate),
+            ("videocodecid", metadata.videocodecid),
+            ("audiodatarate", metadata.audiodatarate),
+            ("audiosamplerate", metadata.audiosamplerate),
+            ("audiosamplesize", metadata.audiosamplesize),
+            ("audiocodecid", metadata.audiocodecid),
+            ("filesize", metadata.filesize),
+        ];
+        for (key, number) in numbers {
+            if let Some(n) = number {
+                h.insert(key.to_string(), Value::Number(n));
+            }
+        }
+        if let Some(b) = metadata.stereo {
+            h.insert("stereo".to_string(), Value::Boolean(b));
+        }
+        if let Some(s) = metadata.encoder {
+            h.insert("encoder".to_string(), Value::Utf8(s));
+        }
+        h.extend(metadata.extra);
+        // onMetaData is conventionally sent as an ECMA array
+        Value::EcmaArray(h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_convert_metadata_to_and_from_value() {
+        let metadata = Metadata {
+            width: Some(1280.0),
+            height: Some(720.0),
+            framerate: Some(30.0),
+            videocodecid: Some(7.0),
+            audiocodecid: Some(10.0),
+            stereo: Some(true),
+            encoder: Some("rtmp-rs".to_string()),
+            ..Default::default()
+        };
+        let value: Value = metadata.clone().into();
+        match &value {
+            Value::EcmaArray(h) => {
+                assert_eq!(h.len(), 7);
+                assert_eq!(h.get("width"), Some(&Value::Number(1280.0)));
+                assert_eq!(h.get("stereo"), Some(&Value::Boolean(true)));
+            }
+            _ => panic!("expected EcmaArray, got {:?}", value),
+        }
+        assert_eq!(Metadata::from_value(&value), Some(metadata));
+    }
+
+    #[test]
+    fn keeps_unknown_properties_in_extra() {
+        let mut h = ValueMap::new();
+        h.insert("width".to_string(), Value::Number(640.0));
+        h.insert("videocodecid".to_string(), Value::Utf8("avc1".to_string()));
+        h.insert("2.1".to_string(), Value::Boolean(false));
+        let metadata = Metadata::from_value(&Value::Object(h.clone())).expect("object");
+        assert_eq!(metadata.width, Some(640.0));
+        assert_eq!(metadata.videocodecid, None);
+        assert_eq!(metadata.extra.len(), 2);
+        assert_eq!(Value::from(metadata), Value::EcmaArray(h));
+        assert_eq!(Metadata::from_value(&Value::Null), None);
+    }
+}
@@ -1,16 +1,32 @@
-use std::collections::HashMap;
 use tokio::prelude::*;
 extern crate proc_macro;
-use crate::amf::Value;
+use crate::amf::{self, Decoder, Value, ValueMap};
+use crate::error::Error;
+use bytes::BufMut;
 use log::{info, trace, warn};
 use std::fmt;
 
+mod metadata;
+pub use metadata::Metadata;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Message {
     pub stream_id: u32,
+    pub timestamp: u32, // milliseconds
+    pub encoding: ObjectEncoding,
     pub data: MessageData,
 }
 
+/// AMF version of command and data messages, agreed with the server by
+/// the `objectEncoding` property of connect.  With AMF3, messages are
+/// sent as types 17 and 15, which switch to AMF3 inside AMF0 values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Primitive)]
+pub enum ObjectEncoding {
+    #[default]
+    Amf0 = 0,
+    Amf3 = 3,
+}
+
 pub const CONNECTION_CHANNEL: Option<u32> = None;
 
 // ideally want to do type checking, can I make an enum that is really an Option?
@@ -26,6 +42,8 @@ impl Message {
     pub fn new(stream_id: Option<u32>, data: MessageData) -> Self {
         Self {
             stream_id: stream_id.unwrap_or(0),
+            timestamp: 0,
+            encoding: Default::default(),
             data,
         }
     }
@@ -33,18 +51,15 @@ impl Message {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum MessageData {
-    // Video(MessageVideo),
+    Audio(Vec<u8>), // FLV AUDIODATA tag body, timestamp is on the Message
+    Video(Vec<u8>), // FLV VIDEODATA tag body, timestamp is on the Message
+    Data { name: String, values: Vec<Value> }, // e.g. onMetaData
     Command(MessageCommand),
     Response(MessageResponse),
     Status(MessageStatus),
-    Error(MessageError),
+    Error(MessageResponse), // '_error' reply to a command
 }
 
-// pub struct MessageVideo {
-//     bytes: Box<[u8]>
-//     timestamp: u32,
-// }
-
 #[derive(Debug, Clone, PartialEq)]
 pub struct MessageCommand {
     pub name: String,
@@ -76,44 +91,9 @@ pub struct MessageStatus {
     pub description: String,
 }
 
-use tokio::sync::mpsc::error::SendError;
-use tokio::sync::oneshot::error::RecvError;
-
 #[derive(Clone, Debug, PartialEq)]
 pub struct MessageError(pub MessageStatus);
 
-impl MessageError {
-    pub(crate) fn new_status(code: &str, description: &str) -> Self {
-        Self(MessageStatus {
-            level: "status".into(),
-            code: code.into(),
-            description: description.into(),
-        })
-    }
-    pub(crate) fn new_error(code: &str, description: &str) -> Self {
-        Self(MessageStatus {
-            level: "error".into(),
-            code: code.into(),
-            description: description.into(),
-        })
-    }
-}
-
-impl<T> From<SendError<T>> for MessageError {
-    fn from(_: SendError<T>) -> Self {
-        Self::new_error(
-            "Something.Closed",
-            "The connection was previously closed due to an IO error or there's a bug",
-        )
-    }
-}
-
-impl From<RecvError> for MessageError {
-    fn from(_: RecvError) -> Self {
-        Self::new_error("A.Bug", "A command id was reused because of a bug")
-    }
-}
-
 impl std::error::Error for MessageError {}
 
 impl fmt::Display for MessageError {
@@ -158,13 +138,24 @@ impl fmt::Display for MessageError {
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.data {
+            MessageData::Audio(bytes) => {
+                write!(f, "Audio {} bytes @{}ms", bytes.len(), self.timestamp)
+            }
+            MessageData::Video(bytes) => {
+                write!(f, "Video {} bytes @{}ms", bytes.len(), self.timestamp)
+            }
+            MessageData::Data { name, values } => {
+                write!(f, "Data '{}' {} values", name, values.len())
+            }
             MessageData::Command(MessageCommand { name, id, .. }) => {
                 write!(f, "Command '{}' #{}", name, id)
             }
             MessageData::Response(MessageResponse { id, .. }) => {
                 write!(f, "Response '_result' #{}", id)
             }
-            MessageData::Error(..) => write!(f, "Response '_error' #(oops unimplemented)"),
+            MessageData::Error(MessageResponse { id, .. }) => {
+                write!(f, "Response '_error' #{}", id)
+            }
             MessageData::Status(MessageStatus {
                 level,
                 code,
@@ -199,7 +190,7 @@ pub struct Status<'a> {
 }
 
 impl Status<'_> {
-    fn from_hash(h: &HashMap<String, Value>) -> Option<Status> {
+    fn from_hash(h: &ValueMap) -> Option<Status> {
         // expected...
         // {"code": Utf8("NetConnection.Connect.Success"),
         //  "level": Utf8("status"),
@@ -230,6 +221,29 @@ impl Status<'_> {
     }
 } // impl Status
 
+impl From<&MessageStatus> for Value {
+    fn from(status: &MessageStatus) -> Self {
+        let mut h = ValueMap::new();
+        h.insert("level".to_string(), Value::Utf8(status.level.clone()));
+        h.insert("code".to_string(), Value::Utf8(status.code.clone()));
+        h.insert(
+            "description".to_string(),
+            Value::Utf8(status.description.clone()),
+        );
+        Value::Object(h)
+    }
+}
+
+impl From<Status<'_>> for MessageStatus {
+    fn from(status: Status) -> Self {
+        Self {
+            level: status.level.to_string(),
+            code: status.code.to_string(),
+            description: status.description.to_string(),
+        }
+    }
+}
+
 impl Message {
     pub fn get_status(&self) -> Option<Status> {
         match &self.data {
@@ -238,59 +252,56 @@ impl Message {
         }
     }
 
-    async fn read_command<T>(mut reader: T) -> io::Result<MessageData>
-    where
-        T: AsyncRead + Unpin,
-    {
-        let cmd_value = Value::read(&mut reader).await.expect("read command name");
+    fn decode_command(decoder: &mut Decoder) -> Result<MessageData, Error> {
+        let cmd_value = decoder.decode()?;
         // trace!(target: "message::read", "cmd_value = {:?}", cmd_value);
 
-        let transaction_id_value = Value::read(&mut reader).await.expect("read transaction id");
+        let transaction_id_value = decoder.decode()?;
         trace!(target: "message::read", "transaction_id_value = {:?}", transaction_id_value);
 
-        let data = Value::read(&mut reader).await.expect("read command data");
+        let data = decoder.decode()?;
         trace!(target: "message::read", "command data = {:?}", data);
 
         if let Value::Number(id) = transaction_id_value {
             if let Value::Utf8(name) = cmd_value {
                 let msg = match name.as_str() {
                     "_result" => {
-                        let opt = Value::read(&mut reader).await.expect("read optional data");
+                        let opt = decoder.decode()?;
                         trace!(target: "message::read", "_result optional data = {:?}", opt);
                         MessageData::Response(MessageResponse { id, data, opt })
                     }
                     "_error" => {
-                        unimplemented!()
-                        // let opt = Value::read(&mut reader).await.expect("read optional data");
-                        // trace!(target: "message::read", "_result optional data = {:?}", opt);
-                        // MessageError { id, data, opt }
+                        let opt = decoder.decode()?;
+                        trace!(target: "message::read", "_error optional data = {:?}", opt);
+                        MessageData::Error(MessageResponse { id, data, opt })
                     }
                     "onStatus" => {
-                        let opt = Value::read(&mut reader).await.expect("read optional data");
+                        let opt = decoder.decode()?;
                         trace!(target: "message::read", "_result optional data = {:#?}", opt);
                         if let Value::Object(h) = opt {
                             let result = Status::from_hash(&h);
                             if let Some(status) = result {
-                                MessageData::Status(MessageStatus {
-                                    level: status.level.to_string(),
-                                    code: status.code.to_string(),
-                                    description: status.description.to_string(),
-                                })
+                                MessageData::Status(status.into())
                             } else {
-                                panic!("unexpected opt hash format {:?} in OnStatus id {}", h, id)
+                                return Err(Error::Protocol(format!(
+                                    "unexpected opt hash format {:?} in onStatus id {}",
+                                    h, id
+                                )));
                             }
                         } else {
-                            panic!("unexpected opt {:?} in OnStatus id {}", opt, id)
+                            return Err(Error::Protocol(format!(
+                                "unexpected opt {:?} in onStatus id {}",
+                                opt, id
+                            )));
                         }
                     }
 
                     _ => {
-                        // not sure what are different ways optional data can appear
-                        // just reading one value for now, since that's what I've tested so far
-                        let opt = match data {
-                            Value::Null => Vec::new(),
-                            _ => vec![Value::read(&mut reader).await.expect("read optional data")],
-                        };
+                        // any arguments follow the command object
+                        let mut opt = Vec::new();
+                        while !decoder.is_empty() {
+                            opt.push(decoder.decode()?);
+                        }
                         trace!(target: "message::read", "command optional data = {:?}", opt);
                         MessageData::Command(MessageCommand {
                             name,
@@ -302,61 +313,138 @@ impl Message {
                 };
                 return Ok(msg);
             } else {
-                panic!("unexpected value for cmd {:?} ", cmd_value)
+                Err(Error::Protocol(format!(
+                    "unexpected value for cmd {:?}",
+                    cmd_value
+                )))
             }
         } else {
-            panic!(
+            Err(Error::Protocol(format!(
                 "unexpected value for transaction_id {:?}",
                 transaction_id_value
-            )
+            )))
         }
     }
 
-    pub async fn read<T>(mut reader: T, chunk_type: u8, chunk_len: u32) -> io::Result<MessageData>
-    where
-        T: AsyncRead + Unpin,
-    {
-        info!(target: "message::read", "read chunk_type {:?}, chunk_len {:?}", chunk_type, chunk_len);
-
-        // TODO: consider reading whole chunk?  or at least checking to see if we read correct amount?
+    // a data message is a name followed by any number of values
+    fn decode_data(decoder: &mut Decoder) -> Result<MessageData, Error> {
+        let name = match decoder.decode()? {
+            Value::Utf8(name) => name,
+            value => {
+                return Err(Error::Protocol(format!(
+                    "expected data message name, got {:?}",
+                    value
+                )))
+            }
+        };
+        let mut values = Vec::new();
+        while !decoder.is_empty() {
+            values.push(decoder.decode()?);
+        }
+        trace!(target: "message::read", "data {:?} values = {:?}", name, values);
+        Ok(MessageData::Data { name, values })
+    }
 
-        match chunk_type {
-            20 => Self::read_command(&mut reader).await, // Command message AMF0
-            _ => panic!("unimplemented read for message chunk type {}", chunk_type),
-        } // match chunk_type
-    } // pub async fn read
+    /// decodes a complete message payload of the given message type
+    pub fn decode(payload: &[u8], message_type: u8) -> Result<MessageData, Error> {
+        info!(target: "message::read", "decode message type {:?}, length {:?}", message_type, payload.len());
+        match message_type {
+            8 => Ok(MessageData::Audio(payload.to_vec())),
+            9 => Ok(MessageData::Video(payload.to_vec())),
+            15 | 17 => {
+                // AMF3 messages start with a format byte, then AMF0 values
+                let (format, values) = payload
+                    .split_first()
+                    .ok_or_else(|| Error::Protocol("empty AMF3 message".to_string()))?;
+                if *format != 0 {
+                    warn!(target: "message::read", "unexpected AMF3 message format {}", format);
+                }
+                let mut decoder = Decoder::new(values);
+                if message_type == 15 {
+                    Self::decode_data(&mut decoder)
+                } else {
+                    Self::decode_command(&mut decoder)
+                }
+            }
+            18 => Self::decode_data(&mut Decoder::new(payload)), // Data message AMF0
+            20 => Self::decode_command(&mut Decoder::new(payload)), // Command message AMF0
+            _ => Err(Error::Unsupported(format!("message type {}", message_type))),
+        } // match message_type
+    }
 
-    pub async fn write<T>(mut writer: T, msg: Message) -> io::Result<()>
+    pub async fn read<T>(
+        mut reader: T,
+        chunk_type: u8,
+        chunk_len: u32,
+    ) -> Result<MessageData, Error>
     where
-        T: AsyncWrite + Unpin,
+        T: AsyncRead + Unpin,
     {
-        info!(target: "message::write", "Message: {:?}", msg);
-        match msg.data {
+        let mut payload = vec![0; chunk_len as usize];
+        reader.read_exact(&mut payload).await?;
+        Self::decode(&payload, chunk_type)
+    }
+
+    /// appends the message payload to the buffer
+    pub fn encode<B: BufMut>(&self, buf: &mut B) -> Result<(), Error> {
+        info!(target: "message::write", "Message: {:?}", self);
+        let is_amf = !matches!(self.data, MessageData::Audio(..) | MessageData::Video(..));
+        if is_amf && self.encoding == ObjectEncoding::Amf3 {
+            buf.put_u8(0);
+        }
+        match &self.data {
+            MessageData::Audio(bytes) | MessageData::Video(bytes) => {
+                buf.put_slice(bytes);
+            }
+            MessageData::Data { name, values } => {
+                amf::encode_str(buf, name);
+                for val in values {
+                    val.encode(buf)?;
+                }
+            }
             MessageData::Command(MessageCommand {
                 name,
                 id,
                 data,
                 opt,
             }) => {
-                Value::write(&mut writer, Value::Utf8(name))
-                    .await
-                    .expect("write command name");
-                Value::write(&mut writer, Value::Number(id.into()))
-                    .await
-                    .expect("write transaction id");
-                Value::write(&mut writer, data)
-                    .await
-                    .expect("write command data");
+                amf::encode_str(buf, name);
+                Value::Number(*id).encode(buf)?;
+                data.encode(buf)?;
                 for val in opt {
-                    Value::write(&mut writer, val)
-                        .await
-                        .expect("write optional info");
+                    val.encode(buf)?;
                 }
             }
-            _ => {
-                panic!("unimplemented write for message {:?}", msg);
+            MessageData::Response(MessageResponse { id, data, opt }) => {
+                amf::encode_str(buf, "_result");
+                Value::Number(*id).encode(buf)?;
+                data.encode(buf)?;
+                opt.encode(buf)?;
+            }
+            MessageData::Error(MessageResponse { id, data, opt }) => {
+                amf::encode_str(buf, "_error");
+                Value::Number(*id).encode(buf)?;
+                data.encode(buf)?;
+                opt.encode(buf)?;
             }
-        } // match chunk_type
+            MessageData::Status(status) => {
+                // sent on the NetStream, not in reply to a transaction
+                amf::encode_str(buf, "onStatus");
+                Value::Number(0.0).encode(buf)?;
+                Value::Null.encode(buf)?;
+                Value::from(status).encode(buf)?;
+            }
+        } // match data
+        Ok(())
+    }
+
+    pub async fn write<T>(mut writer: T, msg: Message) -> Result<(), Error>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let mut buf = Vec::new();
+        msg.encode(&mut buf)?;
+        writer.write_all(&buf).await?;
         Ok(())
     } // pub async fn write
 } // impl Message
@@ -365,7 +453,6 @@ impl Message {
 mod tests {
     use super::*; // importing names from outer (for mod tests) scope.
     use crate::util::bytes_from_hex_string;
-    use std::collections::HashMap;
 
     #[tokio::test]
     async fn can_read_command_response() {
@@ -431,7 +518,7 @@ mod tests {
             .await
             .expect("read");
 
-        let mut data_hash = HashMap::new();
+        let mut data_hash = ValueMap::new();
         data_hash.insert(
             "fmsVer".to_string(),
             Value::Utf8("FMS/5,0,15,5004".to_string()),
@@ -439,9 +526,9 @@ mod tests {
         data_hash.insert("capabilities".to_string(), Value::Number(255.0));
         data_hash.insert("mode".to_string(), Value::Number(1.0));
 
-        // let mut opt_hash = HashMap::new();
+        // let mut opt_hash = ValueMap::new();
 
-        // let mut nested_data = HashMap::new();
+        // let mut nested_data = ValueMap::new();
         // nested_data.insert("version".to_string(), Value::Utf8("5,0,15,5004".to_string()));
         // opt_hash.insert("data".to_string(), Value::Object(nested_data));
 
@@ -477,7 +564,7 @@ mod tests {
             .await
             .expect("read");
 
-        let mut data_hash = HashMap::new();
+        let mut data_hash = ValueMap::new();
         data_hash.insert(
             "fmsVer".to_string(),
             Value::Utf8("FMS/5,0,15,5004".to_string()),
@@ -495,4 +582,199 @@ mod tests {
             }) // end Message::Command
         );
     }
+
+    #[test]
+    fn can_decode_command_arguments_after_null() {
+        // 02 00 04 70 6c 61 79          "play"
+        // 00 00 00 00 00 00 00 00 00    0
+        // 05                            Null
+        // 02 00 03 63 61 6d             "cam"
+        // 00 c0 00 00 00 00 00 00 00    -2
+        let bytes = bytes_from_hex_string(
+            "02 00 04 70 6c 61 79  00 00 00 00 00 00 00 00 00  05
+             02 00 03 63 61 6d  00 c0 00 00 00 00 00 00 00",
+        );
+        let command = MessageData::Command(MessageCommand {
+            name: "play".to_string(),
+            id: 0.0,
+            data: Value::Null,
+            opt: vec![Value::Utf8("cam".to_string()), Value::Number(-2.0)],
+        });
+        assert_eq!(Message::decode(&bytes, 20).expect("decode"), command);
+
+        let mut buf = Vec::new();
+        Message::new(None, command)
+            .encode(&mut buf)
+            .expect("encode");
+        assert_eq!(buf, bytes);
+    }
+
+    #[test]
+    fn can_decode_error_response() {
+        // 02 00 06 5f 65 72 72 6f 72    "_error"
+        // 00 40 08 00 00 00 00 00 00    3
+        // 05                            Null
+        // 03 00 04 63 6f 64 65          { code:
+        // 02 00 03 42 61 64 00 00 09      "Bad" }
+        let bytes = bytes_from_hex_string(
+            "02 00 06 5f 65 72 72 6f 72  00 40 08 00 00 00 00 00 00  05
+             03 00 04 63 6f 64 65  02 00 03 42 61 64  00 00 09",
+        );
+        let data = Message::decode(&bytes, 20).expect("decode");
+        if let MessageData::Error(response) = &data {
+            assert_eq!(response.id, 3.0);
+            assert_eq!(response.get_status().expect("status").code, "Bad");
+        } else {
+            panic!("expected error response, got {:?}", data);
+        }
+
+        let mut buf = Vec::new();
+        Message::new(None, data).encode(&mut buf).expect("encode");
+        assert_eq!(buf, bytes);
+    }
+
+    #[test]
+    fn can_encode_and_decode_status() {
+        let status = MessageStatus {
+            level: "status".to_string(),
+            code: "NetStream.Publish.Start".to_string(),
+            description: "cam is now published.".to_string(),
+        };
+        let mut buf = Vec::new();
+        Message::new(Some(1), MessageData::Status(status.clone()))
+            .encode(&mut buf)
+            .expect("encode");
+        assert_eq!(
+            buf[..11],
+            bytes_from_hex_string("02 00 08 6f 6e 53 74 61 74 75 73")[..]
+        );
+        let data = Message::decode(&buf, 20).expect("decode");
+        assert_eq!(data, MessageData::Status(status));
+    }
+
+    #[test]
+    fn can_not_decode_malformed_messages() {
+        let result = Message::decode(&[0x02, 0x00, 0x05], 20);
+        assert!(matches!(result, Err(Error::Amf(amf::Error::Truncated(5)))));
+
+        // transaction id must be a number
+        let bytes = bytes_from_hex_string("02 00 01 78  05  05");
+        let result = Message::decode(&bytes, 20);
+        assert!(matches!(result, Err(Error::Protocol(..))));
+
+        // shared object messages
+        let result = Message::decode(&bytes, 19);
+        assert!(matches!(result, Err(Error::Unsupported(..))));
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_video() {
+        // AVC keyframe, NALU packet, composition time 0, then NALU data
+        let bytes = bytes_from_hex_string("17 01 00 00 00 00 00 00 02 09 10");
+
+        let buf: &[u8] = &bytes;
+        let m = Message::read(buf, 9, bytes.len() as u32)
+            .await
+            .expect("read");
+        assert_eq!(m, MessageData::Video(bytes.clone()));
+
+        let mut out = Vec::new();
+        Message::write(&mut out, Message::new(Some(1), m))
+            .await
+            .expect("write");
+        assert_eq!(out, bytes);
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_set_data_frame() {
+        // 02 00 0d 40 73 65 74 44 61 74 61 46 72 61 6d 65   "@setDataFrame"
+        // 02 00 0a 6f 6e 4d 65 74 61 44 61 74 61            "onMetaData"
+        // 08 00 00 00 01                                    EcmaArray, 1 property
+        //    00 05 77 69 64 74 68                           "width"
+        //    00 40 84 00 00 00 00 00 00                     Number(640.0)
+        //    00 00 09                                       ObjectEnd
+        let bytes = bytes_from_hex_string(
+            "02 00 0d 40 73 65 74 44 61 74 61 46 72 61 6d 65
+            02 00 0a 6f 6e 4d 65 74 61 44 61 74 61
+            08 00 00 00 01 00 05 77 69 64 74 68 00 40 84 00 00 00 00 00 00 00 00 09",
+        );
+        let metadata = Metadata {
+            width: Some(640.0),
+            ..Default::default()
+        };
+        let data = MessageData::Data {
+            name: "@setDataFrame".to_string(),
+            values: vec![Value::Utf8("onMetaData".to_string()), metadata.into()],
+        };
+
+        let buf: &[u8] = &bytes;
+        let m = Message::read(buf, 18, bytes.len() as u32)
+            .await
+            .expect("read");
+        assert_eq!(m, data);
+
+        let mut out = Vec::new();
+        Message::write(&mut out, Message::new(Some(1), data))
+            .await
+            .expect("write");
+        assert_eq!(out, bytes);
+    }
+
+    #[tokio::test]
+    async fn can_read_and_write_amf3_command() {
+        // 00                             AMF3 message format
+        // 02 00 08 6f 6e 42 57 44 6f 6e 65  "onBWDone"
+        // 00 00 00 00 00 00 00 00 00     Number(0.0)
+        // 05                             Null
+        let bytes = bytes_from_hex_string(
+            "00 02 00 08 6f 6e 42 57 44 6f 6e 65 00 00 00 00 00 00 00 00 00 05",
+        );
+        let data = MessageData::Command(MessageCommand {
+            name: "onBWDone".to_string(),
+            id: 0.0,
+            data: Value::Null,
+            opt: Vec::new(),
+        });
+
+        let buf: &[u8] = &bytes;
+        let m = Message::read(buf, 17, bytes.len() as u32)
+            .await
+            .expect("read");
+        assert_eq!(m, data);
+
+        let mut msg = Message::new(None, data);
+        msg.encoding = ObjectEncoding::Amf3;
+        let mut out = Vec::new();
+        Message::write(&mut out, msg).await.expect("write");
+        assert_eq!(out, bytes);
+    }
+
+    #[tokio::test]
+    async fn can_read_amf3_data() {
+        // 00 then "onTextData", Null
+        let bytes = bytes_from_hex_string("00 02 00 0a 6f 6e 54 65 78 74 44 61 74 61 05");
+        let buf: &[u8] = &bytes;
+        let m = Message::read(buf, 15, bytes.len() as u32)
+            .await
+            .expect("read");
+        assert_eq!(
+            m,
+            MessageData::Data {
+                name: "onTextData".to_string(),
+                values: vec![Value::Null],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn can_read_audio() {
+        // AAC 44kHz stereo, raw frame
+        let bytes = bytes_from_hex_string("af 01 21 00 49 90");
+
+        let buf: &[u8] = &bytes;
+        let m = Message::read(buf, 8, bytes.len() as u32)
+            .await
+            .expect("read");
+        assert_eq!(m, MessageData::Audio(bytes));
+    }
 } // mod tests
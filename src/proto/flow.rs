@@ -0,0 +1,149 @@
+use crate::chunk::LimitType;
+use log::trace;
+use num_traits::FromPrimitive;
+
+/// Byte counting for RTMP flow control.
+///
+/// Inbound, the peer tells us with SetWindowAckSize how many bytes it may
+/// send before expecting an Acknowledgement with the total received so far.
+///
+/// Outbound, the peer limits how many bytes we may send without it having
+/// acknowledged them with SetPeerBandwidth.  Peers that never send an
+/// Acknowledgement are not throttled, since waiting on them would stall.
+#[derive(Debug)]
+pub struct FlowControl {
+    window_ack_size: u32,
+    bytes_received: u32, // sequence numbers wrap around at 2^32
+    last_ack_sent: u32,
+    peer_bandwidth: u32,
+    peer_limit_type: Option<LimitType>,
+    bytes_sent: u32,
+    last_ack_received: Option<u32>,
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        Self {
+            window_ack_size: 2500000,
+            bytes_received: 0,
+            last_ack_sent: 0,
+            peer_bandwidth: u32::MAX,
+            peer_limit_type: None,
+            bytes_sent: 0,
+            last_ack_received: None,
+        }
+    }
+}
+
+impl FlowControl {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set_window_ack_size(&mut self, size: u32) {
+        self.window_ack_size = size;
+    }
+
+    /// counts bytes read from the peer, returning the sequence number to
+    /// acknowledge when a full window has been received since the last ack
+    pub fn received(&mut self, num_bytes: u32) -> Option<u32> {
+        self.bytes_received = self.bytes_received.wrapping_add(num_bytes);
+        let unacked = self.bytes_received.wrapping_sub(self.last_ack_sent);
+        if self.window_ack_size > 0 && unacked >= self.window_ack_size {
+            self.last_ack_sent = self.bytes_received;
+            Some(self.bytes_received)
+        } else {
+            None
+        }
+    }
+
+    /// counts bytes written to the peer
+    pub fn sent(&mut self, num_bytes: u32) {
+        self.bytes_sent = self.bytes_sent.wrapping_add(num_bytes);
+    }
+
+    /// records an Acknowledgement from the peer
+    pub fn acknowledged(&mut self, sequence_number: u32) {
+        self.last_ack_received = Some(sequence_number);
+    }
+
+    /// applies SetPeerBandwidth, returning the resulting outbound window
+    pub fn set_peer_bandwidth(&mut self, size: u32, limit: u8) -> u32 {
+        let limit_type = LimitType::from_u8(limit);
+        let applied = match limit_type {
+            Some(LimitType::Hard) => Some(LimitType::Hard),
+            Some(LimitType::Soft) => Some(LimitType::Soft),
+            Some(LimitType::Dynamic) if self.peer_limit_type == Some(LimitType::Hard) => {
+                Some(LimitType::Hard)
+            }
+            _ => None,
+        };
+        match applied {
+            Some(LimitType::Soft) => self.peer_bandwidth = self.peer_bandwidth.min(size),
+            Some(_) => self.peer_bandwidth = size,
+            None => trace!(target: "rtmp::flow", "ignoring peer bandwidth limit {}", limit),
+        }
+        if applied.is_some() {
+            self.peer_limit_type = applied;
+        }
+        self.peer_bandwidth
+    }
+
+    /// whether the peer has acknowledged enough for us to keep sending
+    pub fn can_send(&self) -> bool {
+        match self.last_ack_received {
+            Some(acked) => self.bytes_sent.wrapping_sub(acked) < self.peer_bandwidth,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acks_each_window_received() {
+        let mut flow = FlowControl::new();
+        flow.set_window_ack_size(100);
+        assert_eq!(flow.received(60), None);
+        assert_eq!(flow.received(60), Some(120));
+        assert_eq!(flow.received(60), None);
+        assert_eq!(flow.received(40), Some(220));
+    }
+
+    #[test]
+    fn acks_across_sequence_number_wrap() {
+        let mut flow = FlowControl::new();
+        flow.set_window_ack_size(100);
+        assert_eq!(flow.received(u32::MAX - 49), Some(u32::MAX - 49));
+        assert_eq!(flow.received(100), Some(50));
+    }
+
+    #[test]
+    fn waits_for_ack_when_peer_bandwidth_used() {
+        let mut flow = FlowControl::new();
+        assert_eq!(flow.set_peer_bandwidth(100, LimitType::Hard as u8), 100);
+        flow.sent(150);
+        // no ack seen yet, so the peer is not known to acknowledge
+        assert!(flow.can_send());
+        flow.acknowledged(40);
+        assert!(!flow.can_send());
+        flow.acknowledged(60);
+        assert!(flow.can_send());
+    }
+
+    #[test]
+    fn applies_peer_bandwidth_limit_types() {
+        let mut flow = FlowControl::new();
+        assert_eq!(
+            flow.set_peer_bandwidth(500, LimitType::Dynamic as u8),
+            u32::MAX
+        );
+        assert_eq!(flow.set_peer_bandwidth(500, LimitType::Soft as u8), 500);
+        assert_eq!(flow.set_peer_bandwidth(800, LimitType::Soft as u8), 500);
+        assert_eq!(flow.set_peer_bandwidth(800, LimitType::Dynamic as u8), 500);
+        assert_eq!(flow.set_peer_bandwidth(800, LimitType::Hard as u8), 800);
+        assert_eq!(flow.set_peer_bandwidth(300, LimitType::Dynamic as u8), 300);
+    }
+}
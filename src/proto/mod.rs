@@ -0,0 +1,627 @@
+//! The RTMP protocol without I/O: a [`Session`] takes the bytes received
+//! from the peer and the messages we want to send, and gives back the bytes
+//! to write along with what the peer told us.  It never blocks or waits,
+//! so it can be driven by any event loop; `Connection` drives one with tokio.
+
+use log::{trace, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::amf::Value;
+use crate::chunk::{Chunk, ChunkReader, ChunkWriter, Event, Signal};
+use crate::connection::handshake::{Handshake, HandshakeProcessResult, PeerType};
+use crate::error::Error;
+use crate::message::*;
+use crate::stream::NetStreamEvent;
+
+mod flow;
+use flow::FlowControl;
+
+/// What the peer told us, from `Session::poll_event`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    HandshakeComplete,
+    /// `_result` or `_error` for a command we sent
+    Response {
+        id: u32,
+        result: Result<MessageResponse, MessageError>,
+    },
+    /// status, data and user control events for a NetStream
+    Stream {
+        stream_id: u32,
+        event: NetStreamEvent,
+    },
+    /// any other message, such as commands from a client, audio and video
+    Message(Message),
+    /// the peer answered a ping sent with this timestamp
+    PingResponse(u32),
+}
+
+/// State of one end of an RTMP connection: the handshake, the chunk
+/// streams in each direction, flow control and the commands waiting for a
+/// response.
+pub struct Session {
+    handshake: Option<Handshake>, // until it completes
+    reader: ChunkReader,
+    writer: ChunkWriter,
+    flow: FlowControl,
+    // last window size we sent with SetWindowAckSize
+    sent_window_ack_size: Option<u32>,
+    encoding: ObjectEncoding,
+    next_transaction_id: u32,
+    commands: HashMap<u32, String>, // names of commands awaiting a response
+    queue: VecDeque<Chunk>,         // waiting for the handshake or flow control
+    output: Vec<u8>,
+    events: VecDeque<SessionEvent>,
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("handshake_complete", &self.is_handshake_complete())
+            .field("flow", &self.flow)
+            .field("commands", &self.commands)
+            .field("queued", &self.queue.len())
+            .field("output", &self.output.len())
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+impl Session {
+    fn new(peer_type: PeerType) -> Self {
+        Self {
+            handshake: Some(Handshake::new(peer_type)),
+            reader: ChunkReader::new(),
+            writer: ChunkWriter::new(),
+            flow: FlowControl::new(),
+            sent_window_ack_size: None,
+            encoding: ObjectEncoding::Amf0,
+            next_transaction_id: 1,
+            commands: HashMap::new(),
+            queue: VecDeque::new(),
+            output: Vec::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Starts the client side of the handshake, its first bytes are ready
+    /// in `take_output` right away.
+    pub fn client() -> Result<Self, Error> {
+        let mut session = Self::new(PeerType::Client);
+        if let Some(handshake) = &mut session.handshake {
+            let c0_and_c1 = handshake.generate_outbound_p0_and_p1()?;
+            session.output.extend_from_slice(&c0_and_c1);
+        }
+        Ok(session)
+    }
+
+    /// Waits for a client to start the handshake.
+    pub fn server() -> Self {
+        Self::new(PeerType::Server)
+    }
+
+    pub fn is_handshake_complete(&self) -> bool {
+        self.handshake.is_none()
+    }
+
+    /// Encoding of the commands sent with `send_command`, see `ObjectEncoding`.
+    pub fn set_object_encoding(&mut self, encoding: ObjectEncoding) {
+        self.encoding = encoding;
+    }
+
+    /// Whether the peer has acknowledged enough of what we sent for more
+    /// messages to go out right away, rather than wait in the queue.
+    pub fn can_send(&self) -> bool {
+        self.flow.can_send()
+    }
+
+    /// Bytes to write to the peer.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// The next thing the peer told us, if any.
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    /// Handles bytes received from the peer, which may complete the
+    /// handshake, messages, or neither.
+    pub fn handle_input(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        match &mut self.handshake {
+            Some(handshake) => match handshake.process_bytes(bytes)? {
+                HandshakeProcessResult::InProgress { response_bytes } => {
+                    self.output.extend_from_slice(&response_bytes);
+                    return Ok(());
+                }
+                HandshakeProcessResult::Completed {
+                    response_bytes,
+                    remaining_bytes,
+                } => {
+                    trace!(target: "rtmp::Session", "handshake completed");
+                    self.output.extend_from_slice(&response_bytes);
+                    self.handshake = None;
+                    self.events.push_back(SessionEvent::HandshakeComplete);
+                    // the peer may not wait for us to send its first chunks
+                    self.reader.push(&remaining_bytes);
+                }
+            },
+            None => self.reader.push(bytes),
+        }
+        loop {
+            match self.reader.decode() {
+                Ok(Some((chunk, num_bytes))) => {
+                    if let Some(sequence_number) = self.flow.received(num_bytes) {
+                        self.write_chunk(Chunk::Control(Signal::AckChunk(sequence_number)))?;
+                    }
+                    match chunk {
+                        Ok(chunk) => self.handle_chunk(chunk)?,
+                        // the message was read in full, so later ones are unaffected
+                        Err(Error::Unsupported(what)) => {
+                            warn!(target: "rtmp::Session", "ignoring unsupported {}", what)
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        self.flush()
+    }
+
+    /// Sends a command that expects a response, returning its transaction
+    /// id.  Ids count up from 1, so a client sends `connect` first.
+    pub fn send_command(
+        &mut self,
+        stream_id: u32,
+        name: &str,
+        data: Value,
+        opt: Vec<Value>,
+    ) -> Result<u32, Error> {
+        let id = self.next_transaction_id;
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1).max(1);
+        let command = MessageData::Command(MessageCommand {
+            name: name.to_string(),
+            id: id.into(),
+            data,
+            opt,
+        });
+        let mut msg = Message::new(Some(stream_id), command);
+        msg.encoding = self.encoding;
+        self.send_message(msg)?;
+        Ok(id)
+    }
+
+    /// Sends a message once the peer has acknowledged enough of what we
+    /// sent before.  Commands with a transaction id other than 0 expect a
+    /// response.
+    pub fn send_message(&mut self, msg: Message) -> Result<(), Error> {
+        self.send_chunk(Chunk::Msg(msg))
+    }
+
+    /// Changes the size of the chunks we send, 128 bytes by default.
+    pub fn set_chunk_size(&mut self, size: u32) -> Result<(), Error> {
+        self.send_chunk(Chunk::Control(Signal::SetChunkSize(size)))
+    }
+
+    /// Tells the peer how many bytes it may send before it should expect
+    /// an acknowledgement from us.
+    pub fn set_window_ack_size(&mut self, size: u32) -> Result<(), Error> {
+        self.send_chunk(Chunk::Control(Signal::SetWindowAckSize(size)))
+    }
+
+    /// Asks the peer to answer with this timestamp, which comes back as
+    /// `SessionEvent::PingResponse`.
+    pub fn ping(&mut self, timestamp: u32) -> Result<(), Error> {
+        let event = Event::PingRequest(timestamp);
+        self.send_chunk(Chunk::Control(Signal::UserControlMessage(event)))
+    }
+
+    /// Stops waiting for a response to the command, e.g. after a timeout,
+    /// so a response that arrives later is dropped.
+    pub fn forget_command(&mut self, id: u32) {
+        self.commands.remove(&id);
+    }
+
+    pub(crate) fn send_chunk(&mut self, chunk: Chunk) -> Result<(), Error> {
+        if let Chunk::Msg(Message {
+            data: MessageData::Command(command),
+            ..
+        }) = &chunk
+        {
+            if command.id != 0.0 {
+                self.commands
+                    .insert(command.id as u32, command.name.clone());
+            }
+        }
+        self.queue.push_back(chunk);
+        self.flush()
+    }
+
+    // encode queued chunks, in order, for as long as the peer lets us
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.is_handshake_complete() {
+            return Ok(());
+        }
+        while let Some(chunk) = self.queue.front() {
+            if let Chunk::Msg(..) = chunk {
+                if !self.flow.can_send() {
+                    break;
+                }
+            }
+            if let Some(chunk) = self.queue.pop_front() {
+                self.write_chunk(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    // encode a chunk, updating writer state in step with any protocol
+    // control message so later chunks follow what the peer expects
+    fn write_chunk(&mut self, chunk: Chunk) -> Result<(), Error> {
+        let (chunk_size, window_ack_size) = match chunk {
+            Chunk::Control(Signal::SetChunkSize(size)) => (Some(size), None),
+            Chunk::Control(Signal::SetWindowAckSize(size)) => (None, Some(size)),
+            _ => (None, None),
+        };
+        let num_bytes = self.writer.encode(&mut self.output, chunk)?;
+        trace!(target: "rtmp::Session", "num bytes: {:?}", num_bytes);
+        self.flow.sent(num_bytes);
+        if let Some(size) = chunk_size {
+            self.writer.set_chunk_size(size);
+        }
+        if window_ack_size.is_some() {
+            self.sent_window_ack_size = window_ack_size;
+        }
+        Ok(())
+    }
+
+    fn handle_chunk(&mut self, chunk: Chunk) -> Result<(), Error> {
+        trace!(target: "rtmp::Session", "handle_chunk {:?}", chunk);
+        let msg = match chunk {
+            Chunk::Control(signal) => return self.handle_signal(signal),
+            Chunk::Msg(msg) => msg,
+        };
+        let event = match msg {
+            Message {
+                data: MessageData::Response(response),
+                ..
+            } => self.response(response.id, Ok(response)),
+            Message {
+                data: MessageData::Error(response),
+                ..
+            } => {
+                let status = match response.get_status() {
+                    Some(status) => status.into(),
+                    None => MessageStatus {
+                        level: "error".to_string(),
+                        code: String::new(),
+                        description: format!("{:?}", response.opt),
+                    },
+                };
+                self.response(response.id, Err(MessageError(status)))
+            }
+            Message {
+                stream_id,
+                data: MessageData::Status(status),
+                ..
+            } if stream_id != 0 => Some(SessionEvent::Stream {
+                stream_id,
+                event: NetStreamEvent::Status(status),
+            }),
+            Message {
+                stream_id,
+                data: MessageData::Data { name, values },
+                ..
+            } if stream_id != 0 => Some(SessionEvent::Stream {
+                stream_id,
+                event: NetStreamEvent::Data { name, values },
+            }),
+            msg => Some(SessionEvent::Message(msg)),
+        };
+        self.events.extend(event);
+        Ok(())
+    }
+
+    // match a response to the command it answers
+    fn response(
+        &mut self,
+        id: f64,
+        result: Result<MessageResponse, MessageError>,
+    ) -> Option<SessionEvent> {
+        let id = id as u32;
+        match self.commands.remove(&id) {
+            Some(name) => {
+                trace!(target: "rtmp::Session", "response to {} #{}", name, id);
+                Some(SessionEvent::Response { id, result })
+            }
+            None => {
+                warn!(target: "rtmp::Session", "Got a response for unregistered command {}", id);
+                None
+            }
+        }
+    }
+
+    fn handle_signal(&mut self, signal: Signal) -> Result<(), Error> {
+        match signal {
+            Signal::SetWindowAckSize(size) => {
+                self.flow.set_window_ack_size(size);
+                trace!(target: "rtmp::Session", "SetWindowAckSize - set window_ack_size {:?}", size)
+            }
+            Signal::SetPeerBandwidth(size, limit) => {
+                let bandwidth = self.flow.set_peer_bandwidth(size, limit);
+                trace!(target: "rtmp::Session", "SetPeerBandwidth - {:?} limit type {:?}, outbound window {:?}", size, limit, bandwidth);
+                // peer expects a reply if this differs from our last window size
+                if self.sent_window_ack_size != Some(size) {
+                    self.write_chunk(Chunk::Control(Signal::SetWindowAckSize(size)))?;
+                }
+            }
+            Signal::SetChunkSize(size) => {
+                self.reader.set_chunk_size(size);
+                trace!(target: "rtmp::Session", "SetChunkSize - set chunk_size {:?}", size)
+            }
+            Signal::AckChunk(sequence_number) => {
+                self.flow.acknowledged(sequence_number);
+                trace!(target: "rtmp::Session", "AckChunk - peer received {:?} bytes", sequence_number)
+            }
+            Signal::Abort(csid) => {
+                self.reader.abort(csid);
+                trace!(target: "rtmp::Session", "Abort - discard message on csid {:?}", csid)
+            }
+            Signal::UserControlMessage(event) => self.handle_user_control(event)?,
+        }
+        Ok(())
+    }
+
+    fn handle_user_control(&mut self, event: Event) -> Result<(), Error> {
+        let (stream_id, stream_event) = match event {
+            Event::PingRequest(timestamp) => {
+                // peers drop connections that don't answer, so reply right away
+                let response = Event::PingResponse(timestamp);
+                return self.write_chunk(Chunk::Control(Signal::UserControlMessage(response)));
+            }
+            Event::PingResponse(timestamp) => {
+                self.events.push_back(SessionEvent::PingResponse(timestamp));
+                return Ok(());
+            }
+            Event::SetBufferLength(stream_id, buffer_length) => {
+                trace!(target: "rtmp::Session", "stream {} buffers {}ms", stream_id, buffer_length);
+                return Ok(());
+            }
+            Event::StreamBegin(id) => (id, NetStreamEvent::Begin),
+            Event::StreamEOF(id) => (id, NetStreamEvent::Eof),
+            Event::StreamDry(id) => (id, NetStreamEvent::Dry),
+            Event::StreamIsRecorded(id) => (id, NetStreamEvent::IsRecorded),
+        };
+        self.events.push_back(SessionEvent::Stream {
+            stream_id,
+            event: stream_event,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::ValueMap;
+    use crate::util::bytes_from_hex_string;
+
+    // move bytes both ways until neither side has anything more to say
+    fn exchange(client: &mut Session, server: &mut Session) {
+        loop {
+            let to_server = client.take_output();
+            let to_client = server.take_output();
+            if to_server.is_empty() && to_client.is_empty() {
+                return;
+            }
+            server.handle_input(&to_server).expect("server input");
+            client.handle_input(&to_client).expect("client input");
+        }
+    }
+
+    // written as a command, the way a server sends it
+    fn result(id: f64, opt: Value) -> MessageData {
+        MessageData::Command(MessageCommand {
+            name: "_result".to_string(),
+            id,
+            data: Value::Null,
+            opt: vec![opt],
+        })
+    }
+
+    fn connected() -> (Session, Session) {
+        let mut client = Session::client().expect("client");
+        let mut server = Session::server();
+        exchange(&mut client, &mut server);
+        assert_eq!(client.poll_event(), Some(SessionEvent::HandshakeComplete));
+        assert_eq!(server.poll_event(), Some(SessionEvent::HandshakeComplete));
+        (client, server)
+    }
+
+    #[test]
+    fn client_starts_handshake() {
+        let mut client = Session::client().expect("client");
+        assert_eq!(client.take_output().len(), 1537); // c0 and c1
+        assert!(!client.is_handshake_complete());
+        assert_eq!(Session::server().take_output().len(), 0);
+    }
+
+    #[test]
+    fn can_handshake() {
+        let (client, server) = connected();
+        assert!(client.is_handshake_complete());
+        assert!(server.is_handshake_complete());
+    }
+
+    #[test]
+    fn messages_wait_for_handshake() {
+        let mut client = Session::client().expect("client");
+        let mut server = Session::server();
+        let c0_and_c1 = client.take_output();
+        let data = MessageData::Data {
+            name: "onMetaData".to_string(),
+            values: vec![],
+        };
+        let msg = Message::new(None, data);
+        client.send_message(msg.clone()).expect("send data");
+        assert_eq!(client.take_output().len(), 0);
+
+        server.handle_input(&c0_and_c1).expect("server input");
+        exchange(&mut client, &mut server);
+        assert_eq!(server.poll_event(), Some(SessionEvent::HandshakeComplete));
+        assert_eq!(server.poll_event(), Some(SessionEvent::Message(msg)));
+    }
+
+    #[test]
+    fn response_matches_command() {
+        let (mut client, mut server) = connected();
+        let id = client
+            .send_command(0, "connect", Value::Null, vec![])
+            .expect("send connect");
+        assert_eq!(id, 1);
+        exchange(&mut client, &mut server);
+        let command = match server.poll_event() {
+            Some(SessionEvent::Message(Message {
+                data: MessageData::Command(command),
+                ..
+            })) => command,
+            event => panic!("expected connect, got {:?}", event),
+        };
+        assert_eq!(command.name, "connect");
+        assert_eq!(command.id, 1.0);
+
+        let mut status = ValueMap::new();
+        status.insert("level".to_string(), Value::Utf8("status".to_string()));
+        status.insert(
+            "code".to_string(),
+            Value::Utf8("NetConnection.Connect.Success".to_string()),
+        );
+        let msg = Message::new(None, result(command.id, Value::Object(status.clone())));
+        server.send_message(msg).expect("send response");
+        exchange(&mut client, &mut server);
+        assert_eq!(
+            client.poll_event(),
+            Some(SessionEvent::Response {
+                id: 1,
+                result: Ok(MessageResponse {
+                    id: 1.0,
+                    data: Value::Null,
+                    opt: Value::Object(status)
+                })
+            })
+        );
+        assert_eq!(
+            client
+                .send_command(0, "createStream", Value::Null, vec![])
+                .ok(),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn ignores_response_to_unknown_command() {
+        let (mut client, mut server) = connected();
+        let msg = Message::new(None, result(7.0, Value::Null));
+        server.send_message(msg).expect("send response");
+        exchange(&mut client, &mut server);
+        assert_eq!(client.poll_event(), None);
+    }
+
+    #[test]
+    fn ignores_response_to_forgotten_command() {
+        let (mut client, mut server) = connected();
+        let id = client
+            .send_command(0, "createStream", Value::Null, vec![])
+            .expect("send createStream");
+        client.forget_command(id);
+        exchange(&mut client, &mut server);
+        let msg = Message::new(None, result(id.into(), Value::Null));
+        server.send_message(msg).expect("send response");
+        exchange(&mut client, &mut server);
+        assert_eq!(client.poll_event(), None);
+    }
+
+    #[test]
+    fn answers_ping() {
+        let (mut client, mut server) = connected();
+        client.ping(1234).expect("ping");
+        exchange(&mut client, &mut server);
+        assert_eq!(server.poll_event(), None);
+        assert_eq!(client.poll_event(), Some(SessionEvent::PingResponse(1234)));
+    }
+
+    #[test]
+    fn routes_stream_events() {
+        let (mut client, mut server) = connected();
+        let begin = Event::StreamBegin(1);
+        server
+            .send_chunk(Chunk::Control(Signal::UserControlMessage(begin)))
+            .expect("send stream begin");
+        let data = MessageData::Data {
+            name: "onMetaData".to_string(),
+            values: vec![],
+        };
+        server
+            .send_message(Message::new(Some(1), data))
+            .expect("send data");
+        exchange(&mut client, &mut server);
+        assert_eq!(
+            client.poll_event(),
+            Some(SessionEvent::Stream {
+                stream_id: 1,
+                event: NetStreamEvent::Begin
+            })
+        );
+        assert_eq!(
+            client.poll_event(),
+            Some(SessionEvent::Stream {
+                stream_id: 1,
+                event: NetStreamEvent::Data {
+                    name: "onMetaData".to_string(),
+                    values: vec![]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn replies_to_peer_bandwidth() {
+        let (mut client, mut server) = connected();
+        server
+            .send_chunk(Chunk::Control(Signal::SetPeerBandwidth(2500000, 2)))
+            .expect("send peer bandwidth");
+        exchange(&mut client, &mut server);
+        assert_eq!(client.sent_window_ack_size, Some(2500000));
+    }
+
+    #[test]
+    fn acknowledges_unsupported_messages() {
+        let (mut client, mut server) = connected();
+        server
+            .set_window_ack_size(32)
+            .expect("send window ack size");
+        let window_ack_size = server.take_output();
+        client
+            .handle_input(&window_ack_size)
+            .expect("window ack size");
+        assert!(client.take_output().is_empty());
+
+        // an aggregate message, type 22
+        let aggregate = bytes_from_hex_string("03 00 00 00 00 00 04 16 01 00 00 00  de ad be ef");
+        client
+            .handle_input(&aggregate)
+            .expect("unsupported message");
+
+        let mut reader = ChunkReader::new();
+        reader.push(&client.take_output());
+        let (chunk, _) = reader.decode().expect("framing").expect("ack");
+        let sequence_number = (window_ack_size.len() + aggregate.len()) as u32;
+        assert_eq!(
+            chunk.expect("chunk"),
+            Chunk::Control(Signal::AckChunk(sequence_number))
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::net::SocketAddr;
+
+use crate::amf::ValueMap;
+use crate::stream::RecordFlag;
+
+/// Decides what clients of a `Server` may do, e.g. checking stream keys
+/// against a database.  Every method accepts by default, so implement only
+/// the ones you need.
+///
+/// ```
+/// use rtmp::server::{async_trait, Rejection, ServerHandler, SessionContext};
+/// use rtmp::RecordFlag;
+///
+/// struct OneKey(String);
+///
+/// #[async_trait]
+/// impl ServerHandler for OneKey {
+///     async fn on_publish(
+///         &self,
+///         context: &SessionContext,
+///         stream_key: &str,
+///         _flag: RecordFlag,
+///     ) -> Result<(), Rejection> {
+///         if context.app.as_deref() == Some("live") && stream_key == self.0 {
+///             Ok(())
+///         } else {
+///             Err(Rejection::new("unknown stream key"))
+///         }
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait ServerHandler: Send + Sync {
+    /// rejected with `NetConnection.Connect.Rejected`, then disconnected
+    async fn on_connect(
+        &self,
+        _context: &SessionContext,
+        _app: &str,
+        _tc_url: Option<&str>,
+        _properties: &ValueMap,
+    ) -> Result<(), Rejection> {
+        Ok(())
+    }
+
+    /// rejected with `NetStream.Publish.BadName`
+    async fn on_publish(
+        &self,
+        _context: &SessionContext,
+        _stream_key: &str,
+        _flag: RecordFlag,
+    ) -> Result<(), Rejection> {
+        Ok(())
+    }
+
+    /// rejected with `NetStream.Play.StreamNotFound`
+    async fn on_play(
+        &self,
+        _context: &SessionContext,
+        _name: &str,
+        _start: f64,
+        _duration: f64,
+    ) -> Result<(), Rejection> {
+        Ok(())
+    }
+
+    /// called once a client that connected goes away
+    async fn on_disconnect(&self, _context: &SessionContext) {}
+}
+
+/// Which client a `ServerHandler` callback is about.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionContext {
+    pub peer_addr: Option<SocketAddr>, // unless the transport was given without one
+    pub app: Option<String>,           // once connected
+    pub stream_id: Option<u32>,        // for publish and play
+}
+
+/// Why a `ServerHandler` refused a client, sent to it as a status
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub code: Option<String>, // None for the usual code of the command
+    pub description: String,
+}
+
+impl Rejection {
+    pub fn new(description: &str) -> Self {
+        Self {
+            code: None,
+            description: description.to_string(),
+        }
+    }
+
+    /// e.g. `NetStream.Publish.BadName` when the stream is already live
+    pub fn with_code(code: &str, description: &str) -> Self {
+        Self {
+            code: Some(code.to_string()),
+            description: description.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{}: {}", code, self.description),
+            None => write!(f, "{}", self.description),
+        }
+    }
+}
+
+// the handler until one is set
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AcceptAll;
+
+impl ServerHandler for AcceptAll {}
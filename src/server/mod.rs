@@ -0,0 +1,1104 @@
+//! Accepts RTMP connections, such as from OBS or ffmpeg publishing a stream.
+//!
+//! ```no_run
+//! # async fn serve() -> Result<(), rtmp::error::Error> {
+//! use rtmp::server::{Server, ServerEvent};
+//!
+//! let mut server = Server::bind("0.0.0.0:1935").await?;
+//! loop {
+//!     let (mut session, _addr) = server.accept().await?;
+//!     tokio::spawn(async move {
+//!         while let Ok(Some(event)) = session.next_event().await {
+//!             if let ServerEvent::Publish { name, .. } = event {
+//!                 println!("publishing {}", name);
+//!             }
+//!         }
+//!     });
+//! }
+//! # }
+//! ```
+
+use log::{trace, warn};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::amf::{Value, ValueMap};
+use crate::chunk::{Chunk, Event, LimitType, Signal};
+use crate::error::Error;
+use crate::message::*;
+use crate::proto::{Session, SessionEvent};
+use crate::stream::{NetStreamEvent, RecordFlag};
+
+mod handler;
+use handler::AcceptAll;
+pub use handler::{Rejection, ServerHandler, SessionContext};
+
+// so handlers can be written without depending on async-trait
+pub use async_trait::async_trait;
+
+// sent to clients when they connect
+const WINDOW_ACK_SIZE: u32 = 2500000;
+const CHUNK_SIZE: u32 = 4096;
+
+// messages queued with a MessageSender
+const CHANNEL_SIZE: usize = 100;
+
+/// Listens for RTMP clients over TCP.
+pub struct Server {
+    listener: TcpListener,
+    handler: Arc<dyn ServerHandler>,
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("listener", &self.listener)
+            .finish()
+    }
+}
+
+impl Server {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener,
+            handler: Arc::new(AcceptAll),
+        })
+    }
+
+    /// Decides what clients accepted from now on may do, by default
+    /// anything.
+    pub fn set_handler<H: ServerHandler + 'static>(&mut self, handler: H) {
+        self.handler = Arc::new(handler);
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Waits for the next client.  The handshake happens once the session
+    /// is asked for events, so a slow client doesn't hold up the others.
+    pub async fn accept(&mut self) -> Result<(ServerSession<TcpStream>, SocketAddr), Error> {
+        let (tcp, addr) = self.listener.accept().await?;
+        tcp.set_nodelay(true)?;
+        trace!(target: "rtmp::Server", "accepted {}", addr);
+        let mut session = ServerSession::new(tcp);
+        session.handler = self.handler.clone();
+        session.peer_addr = Some(addr);
+        Ok((session, addr))
+    }
+}
+
+/// What a client did, from `ServerSession::next_event`.  The session has
+/// already replied to commands, e.g. with `NetStream.Publish.Start`, and
+/// commands the `ServerHandler` rejected or the client got wrong don't show
+/// up here.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    Connect {
+        app: String,
+        tc_url: Option<String>,
+        properties: ValueMap, // everything in the connect command object
+    },
+    CreateStream {
+        stream_id: u32,
+    },
+    Publish {
+        stream_id: u32,
+        name: String, // usually the stream key
+        flag: RecordFlag,
+    },
+    Play {
+        stream_id: u32,
+        name: String,
+        start: f64,    // seconds, -2 for live or recorded, -1 for live only
+        duration: f64, // seconds, -1 until the end
+    },
+    DeleteStream {
+        stream_id: u32,
+    },
+    Data {
+        stream_id: u32,
+        name: String, // e.g. @setDataFrame
+        values: Vec<Value>,
+    },
+    Audio {
+        stream_id: u32,
+        timestamp: u32,
+        data: Vec<u8>, // FLV AUDIODATA tag body
+    },
+    Video {
+        stream_id: u32,
+        timestamp: u32,
+        data: Vec<u8>, // FLV VIDEODATA tag body
+    },
+    // other commands, e.g. releaseStream and FCPublish from encoders
+    Command {
+        stream_id: u32,
+        command: MessageCommand,
+    },
+}
+
+/// One client connection, over TCP from `Server::accept` or any other
+/// transport with `ServerSession::new`.
+pub struct ServerSession<T: AsyncRead + AsyncWrite + Unpin> {
+    transport: T,
+    session: Session,
+    output: Vec<u8>, // encoded, not yet written
+    outgoing_tx: mpsc::Sender<Message>,
+    outgoing_rx: mpsc::Receiver<Message>,
+    handler: Arc<dyn ServerHandler>,
+    peer_addr: Option<SocketAddr>,
+    app: Option<String>, // once connected
+    connected: bool,     // until on_disconnect
+    rejected: bool,      // connect was rejected, so we hang up
+    streams: HashSet<u32>,
+    next_stream_id: u32,
+    events: VecDeque<ServerEvent>,
+    read_buffer: Vec<u8>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> fmt::Debug for ServerSession<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerSession")
+            .field("session", &self.session)
+            .field("peer_addr", &self.peer_addr)
+            .field("app", &self.app)
+            .field("connected", &self.connected)
+            .field("rejected", &self.rejected)
+            .field("streams", &self.streams)
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> ServerSession<T> {
+    pub fn new(transport: T) -> Self {
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(CHANNEL_SIZE);
+        Self {
+            transport,
+            session: Session::server(),
+            output: Vec::new(),
+            outgoing_tx,
+            outgoing_rx,
+            handler: Arc::new(AcceptAll),
+            peer_addr: None,
+            app: None,
+            connected: false,
+            rejected: false,
+            streams: HashSet::new(),
+            next_stream_id: 1,
+            events: VecDeque::new(),
+            read_buffer: vec![0; 4096],
+        }
+    }
+
+    /// The application name the client connected to, e.g. `live`
+    pub fn app(&self) -> Option<&str> {
+        self.app.as_deref()
+    }
+
+    /// Where the client connected from, if known
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// For the `ServerHandler`, when the transport wasn't accepted by a
+    /// `Server`
+    pub fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.peer_addr = Some(addr);
+    }
+
+    /// Decides what this client may do, by default anything.
+    pub fn set_handler<H: ServerHandler + 'static>(&mut self, handler: H) {
+        self.handler = Arc::new(handler);
+    }
+
+    fn context(&self, stream_id: Option<u32>) -> SessionContext {
+        SessionContext {
+            peer_addr: self.peer_addr,
+            app: self.app.clone(),
+            stream_id,
+        }
+    }
+
+    /// Queues messages for the client from elsewhere, e.g. a task sending
+    /// audio and video to a client that is playing.  They are sent while
+    /// `next_event` waits for the client, so keep calling it.
+    pub fn sender(&self) -> MessageSender {
+        MessageSender(self.outgoing_tx.clone())
+    }
+
+    /// Handles what the client sends until it does something the
+    /// application should know about, or `None` once it disconnects.
+    ///
+    /// Cancelling this, e.g. in `select!`, loses no input or output, except
+    /// that a command is dropped if it happens while the `ServerHandler`
+    /// is deciding on it.
+    pub async fn next_event(&mut self) -> Result<Option<ServerEvent>, Error> {
+        let result = self.read_event().await;
+        if !matches!(result, Ok(Some(..))) && self.connected {
+            self.connected = false;
+            self.handler.on_disconnect(&self.context(None)).await;
+        }
+        result
+    }
+
+    async fn read_event(&mut self) -> Result<Option<ServerEvent>, Error> {
+        loop {
+            self.handle_session_events().await?;
+            self.write_output().await?;
+            if self.rejected {
+                self.transport.shutdown().await?;
+                return Ok(None);
+            }
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            // queued messages wait while the client is behind on acknowledgements
+            let can_send = self.session.can_send();
+            tokio::select! {
+                Some(msg) = self.outgoing_rx.recv(), if can_send => {
+                    self.session.send_message(msg)?;
+                }
+                num_bytes = self.transport.read(&mut self.read_buffer) => {
+                    let num_bytes = num_bytes?;
+                    if num_bytes == 0 {
+                        trace!(target: "rtmp::ServerSession", "client disconnected");
+                        return Ok(None);
+                    }
+                    self.session.handle_input(&self.read_buffer[..num_bytes])?;
+                }
+            }
+        }
+    }
+
+    /// Sends a message to the client, e.g. audio and video for a stream it
+    /// is playing.
+    pub async fn send_message(&mut self, msg: Message) -> Result<(), Error> {
+        self.session.send_message(msg)?;
+        self.write_output().await
+    }
+
+    // output stays buffered until written, in case this is cancelled
+    async fn write_output(&mut self) -> Result<(), Error> {
+        self.output.extend(self.session.take_output());
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        while !self.output.is_empty() {
+            let num_bytes = self.transport.write(&self.output).await?;
+            if num_bytes == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            self.output.drain(..num_bytes);
+        }
+        self.transport.flush().await?;
+        Ok(())
+    }
+
+    async fn handle_session_events(&mut self) -> Result<(), Error> {
+        while let Some(event) = self.session.poll_event() {
+            if self.rejected {
+                break;
+            }
+            match event {
+                SessionEvent::Message(msg) => self.handle_message(msg).await?,
+                // data on a stream, usually metadata from a publisher
+                SessionEvent::Stream {
+                    stream_id,
+                    event: NetStreamEvent::Data { name, values },
+                } => self.events.push_back(ServerEvent::Data {
+                    stream_id,
+                    name,
+                    values,
+                }),
+                event => trace!(target: "rtmp::ServerSession", "{:?}", event),
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, msg: Message) -> Result<(), Error> {
+        let Message {
+            stream_id,
+            timestamp,
+            data,
+            ..
+        } = msg;
+        let event = match data {
+            MessageData::Command(command) => return self.handle_command(stream_id, command).await,
+            MessageData::Audio(data) => ServerEvent::Audio {
+                stream_id,
+                timestamp,
+                data,
+            },
+            MessageData::Video(data) => ServerEvent::Video {
+                stream_id,
+                timestamp,
+                data,
+            },
+            MessageData::Data { name, values } => ServerEvent::Data {
+                stream_id,
+                name,
+                values,
+            },
+            data => {
+                warn!(target: "rtmp::ServerSession", "unexpected message from client {:?}", data);
+                return Ok(());
+            }
+        };
+        self.events.push_back(event);
+        Ok(())
+    }
+
+    async fn handle_command(
+        &mut self,
+        stream_id: u32,
+        command: MessageCommand,
+    ) -> Result<(), Error> {
+        trace!(target: "rtmp::ServerSession", "command {:?} on stream {}", command, stream_id);
+        if self.app.is_none() && command.name != "connect" {
+            let description = format!("{} before connect", command.name);
+            let rejection = Rejection::new(&description);
+            return self.reply_error(command.id, "NetConnection.Call.Failed", rejection);
+        }
+        let event = match command.name.as_str() {
+            "connect" => self.connect(command).await?,
+            "createStream" => {
+                let stream_id = self.next_stream_id;
+                self.next_stream_id += 1;
+                self.streams.insert(stream_id);
+                self.respond(command.id, Value::Null, Value::Number(stream_id.into()))?;
+                Some(ServerEvent::CreateStream { stream_id })
+            }
+            "publish" => {
+                let name = match self.stream_name(stream_id, &command) {
+                    Ok(name) => name,
+                    Err(rejection) => {
+                        return self.reject(stream_id, "NetStream.Publish.BadName", rejection);
+                    }
+                };
+                // encoders may leave out the type, which means live
+                let flag = match command.opt.get(1).and_then(Value::as_str) {
+                    Some(flag) => flag.parse().unwrap_or(RecordFlag::Live),
+                    None => RecordFlag::Live,
+                };
+                let context = self.context(Some(stream_id));
+                match self.handler.on_publish(&context, &name, flag).await {
+                    Ok(()) => {
+                        let description = format!("{} is now published.", name);
+                        self.send_status(stream_id, "NetStream.Publish.Start", &description)?;
+                        Some(ServerEvent::Publish {
+                            stream_id,
+                            name,
+                            flag,
+                        })
+                    }
+                    Err(rejection) => {
+                        self.reject(stream_id, "NetStream.Publish.BadName", rejection)?;
+                        None
+                    }
+                }
+            }
+            "play" => {
+                let name = match self.stream_name(stream_id, &command) {
+                    Ok(name) => name,
+                    Err(rejection) => {
+                        return self.reject(stream_id, "NetStream.Play.Failed", rejection)
+                    }
+                };
+                let start = command.opt.get(1).and_then(Value::as_number);
+                let start = start.unwrap_or(-2.0);
+                let duration = command.opt.get(2).and_then(Value::as_number);
+                let duration = duration.unwrap_or(-1.0);
+                let context = self.context(Some(stream_id));
+                match self.handler.on_play(&context, &name, start, duration).await {
+                    Ok(()) => {
+                        let begin = Event::StreamBegin(stream_id);
+                        self.session
+                            .send_chunk(Chunk::Control(Signal::UserControlMessage(begin)))?;
+                        let description = format!("Playing and resetting {}.", name);
+                        self.send_status(stream_id, "NetStream.Play.Reset", &description)?;
+                        let description = format!("Started playing {}.", name);
+                        self.send_status(stream_id, "NetStream.Play.Start", &description)?;
+                        Some(ServerEvent::Play {
+                            stream_id,
+                            name,
+                            start,
+                            duration,
+                        })
+                    }
+                    Err(rejection) => {
+                        self.reject(stream_id, "NetStream.Play.StreamNotFound", rejection)?;
+                        None
+                    }
+                }
+            }
+            "deleteStream" => {
+                let stream_id = match command.opt.first().and_then(Value::as_number) {
+                    Some(id) => id as u32,
+                    None => {
+                        warn!(target: "rtmp::ServerSession", "deleteStream without a stream id");
+                        return Ok(());
+                    }
+                };
+                if !self.streams.remove(&stream_id) {
+                    warn!(target: "rtmp::ServerSession", "deleteStream for unknown stream {}", stream_id);
+                    return Ok(());
+                }
+                Some(ServerEvent::DeleteStream { stream_id })
+            }
+            _ => Some(ServerEvent::Command { stream_id, command }),
+        };
+        self.events.extend(event);
+        Ok(())
+    }
+
+    async fn connect(&mut self, command: MessageCommand) -> Result<Option<ServerEvent>, Error> {
+        if self.app.is_some() {
+            let rejection = Rejection::new("already connected");
+            self.reply_error(command.id, "NetConnection.Connect.Failed", rejection)?;
+            return Ok(None);
+        }
+        let properties = match command.data.properties() {
+            Some(properties) => properties.clone(),
+            None => {
+                let rejection = Rejection::new("connect without a command object");
+                self.reply_error(command.id, "NetConnection.Connect.Failed", rejection)?;
+                return Ok(None);
+            }
+        };
+        let app = Value::object_get_string(&properties, "app").unwrap_or_default();
+        let tc_url = Value::object_get_string(&properties, "tcUrl");
+        let verdict = self
+            .handler
+            .on_connect(&self.context(None), &app, tc_url.as_deref(), &properties)
+            .await;
+        if let Err(rejection) = verdict {
+            trace!(target: "rtmp::ServerSession", "rejected connect to {}: {}", app, rejection);
+            self.reply_error(command.id, "NetConnection.Connect.Rejected", rejection)?;
+            self.rejected = true;
+            return Ok(None);
+        }
+
+        self.session.set_window_ack_size(WINDOW_ACK_SIZE)?;
+        let bandwidth = Signal::SetPeerBandwidth(WINDOW_ACK_SIZE, LimitType::Dynamic as u8);
+        self.session.send_chunk(Chunk::Control(bandwidth))?;
+        self.session.set_chunk_size(CHUNK_SIZE)?;
+
+        let mut server = ValueMap::new();
+        server.insert("fmsVer".to_string(), "FMS/3,0,1,123".into());
+        server.insert("capabilities".to_string(), Value::Number(31.0));
+        // replies are AMF0 whatever the client asked for
+        let mut info = ValueMap::new();
+        info.insert("level".to_string(), "status".into());
+        info.insert("code".to_string(), "NetConnection.Connect.Success".into());
+        info.insert("description".to_string(), "Connection succeeded.".into());
+        info.insert("objectEncoding".to_string(), Value::Number(0.0));
+        self.respond(command.id, Value::Object(server), Value::Object(info))?;
+
+        self.app = Some(app.clone());
+        self.connected = true;
+        Ok(Some(ServerEvent::Connect {
+            app,
+            tc_url,
+            properties,
+        }))
+    }
+
+    // the name publish and play take, on a stream the client created
+    fn stream_name(&self, stream_id: u32, command: &MessageCommand) -> Result<String, Rejection> {
+        if !self.streams.contains(&stream_id) {
+            let description = format!(
+                "{} on stream {} that was not created",
+                command.name, stream_id
+            );
+            return Err(Rejection::new(&description));
+        }
+        match command.opt.first().and_then(Value::as_string) {
+            Some(name) => Ok(name),
+            None => Err(Rejection::new(&format!("{} without a name", command.name))),
+        }
+    }
+
+    fn respond(&mut self, id: f64, data: Value, opt: Value) -> Result<(), Error> {
+        let response = MessageData::Response(MessageResponse { id, data, opt });
+        self.session.send_message(Message::new(None, response))
+    }
+
+    fn send_status(&mut self, stream_id: u32, code: &str, description: &str) -> Result<(), Error> {
+        let status = MessageStatus {
+            level: "status".to_string(),
+            code: code.to_string(),
+            description: description.to_string(),
+        };
+        let msg = Message::new(Some(stream_id), MessageData::Status(status));
+        self.session.send_message(msg)
+    }
+
+    // answer a command with _error, with the handler's code or the usual one
+    fn reply_error(&mut self, id: f64, code: &str, rejection: Rejection) -> Result<(), Error> {
+        trace!(target: "rtmp::ServerSession", "command #{} failed: {}", id, rejection);
+        let status = MessageStatus {
+            level: "error".to_string(),
+            code: rejection.code.unwrap_or_else(|| code.to_string()),
+            description: rejection.description,
+        };
+        let response = MessageResponse {
+            id,
+            data: Value::Null,
+            opt: Value::from(&status),
+        };
+        self.session
+            .send_message(Message::new(None, MessageData::Error(response)))
+    }
+
+    // tell the client why, with the handler's code or the usual one
+    fn reject(&mut self, stream_id: u32, code: &str, rejection: Rejection) -> Result<(), Error> {
+        trace!(target: "rtmp::ServerSession", "rejected on stream {}: {}", stream_id, rejection);
+        let status = MessageStatus {
+            level: "error".to_string(),
+            code: rejection.code.unwrap_or_else(|| code.to_string()),
+            description: rejection.description,
+        };
+        let msg = Message::new(Some(stream_id), MessageData::Status(status));
+        self.session.send_message(msg)
+    }
+}
+
+/// Sends messages through a `ServerSession`, see `ServerSession::sender`.
+#[derive(Debug, Clone)]
+pub struct MessageSender(mpsc::Sender<Message>);
+
+impl MessageSender {
+    /// Fails with `Error::NotConnected` once the session is gone.
+    pub async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        self.0.send(msg).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Connection;
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+    use tokio::net::UnixStream;
+    use url::Url;
+
+    // lets clients connect to live, publish with one key and play nothing,
+    // keeping the context of each call
+    #[derive(Default)]
+    struct Handler {
+        disconnects: Arc<AtomicU32>,
+        contexts: Arc<Mutex<Vec<SessionContext>>>,
+    }
+
+    impl Handler {
+        fn record(&self, context: &SessionContext) {
+            self.contexts.lock().unwrap().push(context.clone());
+        }
+    }
+
+    #[async_trait]
+    impl ServerHandler for Handler {
+        async fn on_connect(
+            &self,
+            context: &SessionContext,
+            app: &str,
+            _tc_url: Option<&str>,
+            _properties: &ValueMap,
+        ) -> Result<(), Rejection> {
+            self.record(context);
+            match app {
+                "live" => Ok(()),
+                _ => Err(Rejection::new("unknown app")),
+            }
+        }
+
+        async fn on_publish(
+            &self,
+            context: &SessionContext,
+            stream_key: &str,
+            _flag: RecordFlag,
+        ) -> Result<(), Rejection> {
+            self.record(context);
+            match stream_key {
+                "secret" => Ok(()),
+                _ => Err(Rejection::new("bad stream key")),
+            }
+        }
+
+        async fn on_play(
+            &self,
+            context: &SessionContext,
+            _name: &str,
+            _start: f64,
+            _duration: f64,
+        ) -> Result<(), Rejection> {
+            self.record(context);
+            Err(Rejection::with_code(
+                "NetStream.Play.Failed",
+                "nothing to play",
+            ))
+        }
+
+        async fn on_disconnect(&self, context: &SessionContext) {
+            self.record(context);
+            self.disconnects.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // collect everything the client does until it disconnects
+    fn spawn_session<S>(
+        mut session: ServerSession<S>,
+    ) -> tokio::task::JoinHandle<Result<Vec<ServerEvent>, Error>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let mut events = Vec::new();
+            while let Some(event) = session.next_event().await? {
+                events.push(event);
+            }
+            Ok(events)
+        })
+    }
+
+    // drive a client session over the socket until it has an event
+    async fn client_event(client: &mut Session, socket: &mut UnixStream) -> SessionEvent {
+        let mut buf = [0_u8; 4096];
+        loop {
+            socket
+                .write_all(&client.take_output())
+                .await
+                .expect("write");
+            if let Some(event) = client.poll_event() {
+                return event;
+            }
+            let num_bytes = socket.read(&mut buf).await.expect("read");
+            assert!(num_bytes > 0, "server closed the connection");
+            client
+                .handle_input(&buf[..num_bytes])
+                .expect("client input");
+        }
+    }
+
+    fn command(name: &str, args: Vec<Value>) -> MessageData {
+        MessageData::Command(MessageCommand {
+            name: name.to_string(),
+            id: 0.0,
+            data: Value::Null,
+            opt: args,
+        })
+    }
+
+    fn status(code: &str, description: &str) -> SessionEvent {
+        SessionEvent::Stream {
+            stream_id: 1,
+            event: NetStreamEvent::Status(MessageStatus {
+                level: "status".to_string(),
+                code: code.to_string(),
+                description: description.to_string(),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_can_publish() {
+        let mut server = Server::bind("127.0.0.1:0").await.expect("bind");
+        let port = server.local_addr().expect("local addr").port();
+        let accepted = tokio::spawn(async move {
+            let (session, addr) = server.accept().await.expect("accept");
+            assert_eq!(session.peer_addr(), Some(addr));
+            session
+        });
+
+        let url = Url::parse(&format!("rtmp://127.0.0.1:{}/live", port)).expect("url");
+        let mut conn = Connection::new(url);
+        let connecting = tokio::spawn(async move {
+            let response = conn.connect().await.map(|_| ());
+            (conn, response)
+        });
+        let mut session = accepted.await.expect("accept task");
+        match session.next_event().await.expect("connect") {
+            Some(ServerEvent::Connect { app, tc_url, .. }) => {
+                assert_eq!(app, "live");
+                assert_eq!(
+                    tc_url.as_deref(),
+                    Some(&*format!("rtmp://127.0.0.1:{}/live", port))
+                );
+            }
+            event => panic!("expected connect, got {:?}", event),
+        }
+        assert_eq!(session.app(), Some("live"));
+        let events = tokio::spawn(async move {
+            let create = session.next_event().await.expect("createStream");
+            let publish = session.next_event().await.expect("publish");
+            (session, [create, publish])
+        });
+
+        let (mut conn, response) = connecting.await.expect("connect task");
+        response.expect("connect response");
+        assert!(conn.is_connected());
+        let (mut stream, _) = conn.new_stream().await.expect("new stream");
+        assert_eq!(stream.id, 1);
+        stream
+            .publish("cam", RecordFlag::Live)
+            .await
+            .expect("publish");
+        match stream.next().await {
+            Some(NetStreamEvent::Status(status)) => {
+                assert_eq!(status.code, "NetStream.Publish.Start")
+            }
+            event => panic!("expected publish status, got {:?}", event),
+        }
+
+        let (_session, events) = events.await.expect("session task");
+        assert_eq!(
+            events,
+            [
+                Some(ServerEvent::CreateStream { stream_id: 1 }),
+                Some(ServerEvent::Publish {
+                    stream_id: 1,
+                    name: "cam".to_string(),
+                    flag: RecordFlag::Live
+                })
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn client_can_play_and_delete_stream() {
+        let (mut socket, server_socket) = UnixStream::pair().expect("socket pair");
+        let events = spawn_session(ServerSession::new(server_socket));
+        let mut client = Session::client().expect("client");
+        assert_eq!(
+            client_event(&mut client, &mut socket).await,
+            SessionEvent::HandshakeComplete
+        );
+
+        let mut properties = ValueMap::new();
+        properties.insert("app".to_string(), "vod".into());
+        let properties = Value::Object(properties);
+        client
+            .send_command(0, "connect", properties.clone(), vec![])
+            .expect("connect");
+        match client_event(&mut client, &mut socket).await {
+            SessionEvent::Response {
+                id: 1,
+                result: Ok(response),
+            } => {
+                let status = response.get_status().expect("status");
+                assert_eq!(status.code, "NetConnection.Connect.Success");
+            }
+            event => panic!("expected connect response, got {:?}", event),
+        }
+        client
+            .send_command(0, "createStream", Value::Null, vec![])
+            .expect("createStream");
+        match client_event(&mut client, &mut socket).await {
+            SessionEvent::Response {
+                id: 2,
+                result: Ok(response),
+            } => {
+                assert_eq!(response.opt, Value::Number(1.0))
+            }
+            event => panic!("expected createStream response, got {:?}", event),
+        }
+
+        let play = command("play", vec!["movie".into()]);
+        client
+            .send_message(Message::new(Some(1), play))
+            .expect("play");
+        assert_eq!(
+            client_event(&mut client, &mut socket).await,
+            SessionEvent::Stream {
+                stream_id: 1,
+                event: NetStreamEvent::Begin
+            }
+        );
+        assert_eq!(
+            client_event(&mut client, &mut socket).await,
+            status("NetStream.Play.Reset", "Playing and resetting movie.")
+        );
+        assert_eq!(
+            client_event(&mut client, &mut socket).await,
+            status("NetStream.Play.Start", "Started playing movie.")
+        );
+
+        let delete = command("deleteStream", vec![Value::Number(1.0)]);
+        client
+            .send_message(Message::new(None, delete))
+            .expect("deleteStream");
+        socket
+            .write_all(&client.take_output())
+            .await
+            .expect("write");
+        drop(socket);
+
+        let events = events.await.expect("session task").expect("events");
+        assert_eq!(
+            events,
+            [
+                ServerEvent::Connect {
+                    app: "vod".to_string(),
+                    tc_url: None,
+                    properties: properties.properties().cloned().unwrap_or_default()
+                },
+                ServerEvent::CreateStream { stream_id: 1 },
+                ServerEvent::Play {
+                    stream_id: 1,
+                    name: "movie".to_string(),
+                    start: -2.0,
+                    duration: -1.0
+                },
+                ServerEvent::DeleteStream { stream_id: 1 },
+            ]
+        );
+    }
+
+    // the session answers mistakes with an error and carries on
+    #[tokio::test]
+    async fn client_mistakes_get_error_replies() {
+        let (mut socket, server_socket) = UnixStream::pair().expect("socket pair");
+        let events = spawn_session(ServerSession::new(server_socket));
+        let mut client = Session::client().expect("client");
+        client_event(&mut client, &mut socket).await;
+        let failed = |code: &str, description: &str| MessageStatus {
+            level: "error".to_string(),
+            code: code.to_string(),
+            description: description.to_string(),
+        };
+
+        client
+            .send_command(0, "createStream", Value::Null, vec![])
+            .expect("createStream");
+        let mut properties = ValueMap::new();
+        properties.insert("app".to_string(), "live".into());
+        let properties = Value::Object(properties);
+        client
+            .send_command(0, "connect", properties.clone(), vec![])
+            .expect("connect");
+        client
+            .send_command(0, "connect", properties, vec![])
+            .expect("connect again");
+        let expected = [
+            Err(failed(
+                "NetConnection.Call.Failed",
+                "createStream before connect",
+            )),
+            Ok(()),
+            Err(failed("NetConnection.Connect.Failed", "already connected")),
+        ];
+        for (id, expected) in (1..).zip(&expected) {
+            match client_event(&mut client, &mut socket).await {
+                SessionEvent::Response { id: n, result } => {
+                    assert_eq!(n, id);
+                    assert_eq!(
+                        result.map(|_| ()).map_err(|MessageError(status)| status),
+                        *expected
+                    );
+                }
+                event => panic!("expected response #{}, got {:?}", id, event),
+            }
+        }
+
+        let publish = command("publish", vec!["cam".into()]);
+        client
+            .send_message(Message::new(Some(1), publish))
+            .expect("publish");
+        assert_eq!(
+            client_event(&mut client, &mut socket).await,
+            SessionEvent::Stream {
+                stream_id: 1,
+                event: NetStreamEvent::Status(failed(
+                    "NetStream.Publish.BadName",
+                    "publish on stream 1 that was not created"
+                )),
+            }
+        );
+        client
+            .send_message(Message::new(None, command("deleteStream", vec![])))
+            .expect("deleteStream");
+        client
+            .send_command(0, "createStream", Value::Null, vec![])
+            .expect("createStream");
+        match client_event(&mut client, &mut socket).await {
+            SessionEvent::Response {
+                id: 4,
+                result: Ok(response),
+            } => assert_eq!(response.opt, Value::Number(1.0)),
+            event => panic!("expected createStream response, got {:?}", event),
+        }
+        client
+            .send_message(Message::new(Some(1), command("play", vec![])))
+            .expect("play");
+        assert_eq!(
+            client_event(&mut client, &mut socket).await,
+            SessionEvent::Stream {
+                stream_id: 1,
+                event: NetStreamEvent::Status(failed(
+                    "NetStream.Play.Failed",
+                    "play without a name"
+                )),
+            }
+        );
+        drop(socket);
+
+        let events = events.await.expect("session task").expect("events");
+        assert!(matches!(
+            events[..],
+            [
+                ServerEvent::Connect { .. },
+                ServerEvent::CreateStream { stream_id: 1 }
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn handler_can_reject_connect() {
+        let (socket, server_socket) = UnixStream::pair().expect("socket pair");
+        let mut session = ServerSession::new(server_socket);
+        let handler = Handler::default();
+        let disconnects = handler.disconnects.clone();
+        session.set_handler(handler);
+        let events = spawn_session(session);
+
+        let url = Url::parse("rtmp://localhost/vod").expect("url");
+        let mut conn = Connection::with_transport(url, socket);
+        match conn.connect().await {
+            Err(Error::Command(MessageError(status))) => {
+                assert_eq!(status.code, "NetConnection.Connect.Rejected");
+                assert_eq!(status.description, "unknown app");
+            }
+            result => panic!("expected connect to be rejected, got {:?}", result),
+        }
+        assert!(!conn.is_connected());
+
+        let events = events.await.expect("session task").expect("events");
+        assert_eq!(events, []);
+        assert_eq!(disconnects.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn handler_can_reject_publish_and_play() {
+        let (mut socket, server_socket) = UnixStream::pair().expect("socket pair");
+        let mut session = ServerSession::new(server_socket);
+        let peer_addr = "192.0.2.1:50000".parse().expect("addr");
+        session.set_peer_addr(peer_addr);
+        let handler = Handler::default();
+        let disconnects = handler.disconnects.clone();
+        let contexts = handler.contexts.clone();
+        session.set_handler(handler);
+        let events = spawn_session(session);
+
+        let mut client = Session::client().expect("client");
+        client_event(&mut client, &mut socket).await;
+        let mut properties = ValueMap::new();
+        properties.insert("app".to_string(), "live".into());
+        client
+            .send_command(0, "connect", Value::Object(properties), vec![])
+            .expect("connect");
+        client
+            .send_command(0, "createStream", Value::Null, vec![])
+            .expect("createStream");
+        for id in 1..=2 {
+            match client_event(&mut client, &mut socket).await {
+                SessionEvent::Response {
+                    id: n,
+                    result: Ok(_),
+                } => assert_eq!(n, id),
+                event => panic!("expected response #{}, got {:?}", id, event),
+            }
+        }
+
+        let publish = command("publish", vec!["guess".into(), "live".into()]);
+        client
+            .send_message(Message::new(Some(1), publish))
+            .expect("publish");
+        let play = command("play", vec!["movie".into()]);
+        client
+            .send_message(Message::new(Some(1), play))
+            .expect("play");
+        for (code, description) in &[
+            ("NetStream.Publish.BadName", "bad stream key"),
+            ("NetStream.Play.Failed", "nothing to play"),
+        ] {
+            let expected = SessionEvent::Stream {
+                stream_id: 1,
+                event: NetStreamEvent::Status(MessageStatus {
+                    level: "error".to_string(),
+                    code: code.to_string(),
+                    description: description.to_string(),
+                }),
+            };
+            assert_eq!(client_event(&mut client, &mut socket).await, expected);
+        }
+        drop(socket);
+
+        let events = events.await.expect("session task").expect("events");
+        assert!(matches!(
+            events[..],
+            [
+                ServerEvent::Connect { .. },
+                ServerEvent::CreateStream { stream_id: 1 }
+            ]
+        ));
+        assert_eq!(disconnects.load(Ordering::SeqCst), 1);
+
+        let context = |app: Option<&str>, stream_id| SessionContext {
+            peer_addr: Some(peer_addr),
+            app: app.map(str::to_string),
+            stream_id,
+        };
+        assert_eq!(
+            *contexts.lock().unwrap(),
+            [
+                context(None, None),
+                context(Some("live"), Some(1)),
+                context(Some("live"), Some(1)),
+                context(Some("live"), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn can_send_while_waiting_for_events() {
+        let (mut socket, server_socket) = UnixStream::pair().expect("socket pair");
+        let session = ServerSession::new(server_socket);
+        let mut sender = session.sender();
+        let events = spawn_session(session);
+
+        let mut client = Session::client().expect("client");
+        client_event(&mut client, &mut socket).await;
+        let mut properties = ValueMap::new();
+        properties.insert("app".to_string(), "live".into());
+        client
+            .send_command(0, "connect", Value::Object(properties), vec![])
+            .expect("connect");
+        client_event(&mut client, &mut socket).await;
+
+        // the session task is waiting for the client to say something
+        let audio = Message::new(Some(1), MessageData::Audio(vec![0xaf, 0x01]));
+        sender.send(audio.clone()).await.expect("send audio");
+        assert_eq!(
+            client_event(&mut client, &mut socket).await,
+            SessionEvent::Message(audio)
+        );
+        drop(socket);
+
+        let events = events.await.expect("session task").expect("events");
+        assert!(matches!(events[..], [ServerEvent::Connect { .. }]));
+        let audio = Message::new(Some(1), MessageData::Audio(vec![]));
+        assert!(matches!(sender.send(audio).await, Err(Error::NotConnected)));
+    }
+}
@@ -1,5 +1,7 @@
 mod flag;
 use crate::amf::Value;
+use crate::chunk::{Event, Signal};
+use crate::error::Error;
 use crate::message::*;
 use crate::Connection;
 pub use flag::RecordFlag;
@@ -12,15 +14,27 @@ use std::task::{Context, Poll};
 use tokio::runtime::Handle;
 use tokio::sync::mpsc;
 
+/// Notifications for a NetStream: status messages from the server along
+/// with the user control events that refer to this stream
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetStreamEvent {
+    Status(MessageStatus),
+    Begin,                                     // stream is ready to be used
+    Eof,                                       // playback is over, no more data will be sent
+    Dry,                                       // no data for now, more may follow
+    IsRecorded,                                // stream is recorded, not live
+    Data { name: String, values: Vec<Value> }, // e.g. onMetaData, onCuePoint
+}
+
 pub struct NetStream {
     pub id: u32,
     cn: Connection,
-    messages: mpsc::Receiver<MessageStatus>,
+    messages: mpsc::Receiver<NetStreamEvent>,
     state: NetStreamState,
 }
 
 impl Stream for NetStream {
-    type Item = MessageStatus;
+    type Item = NetStreamEvent;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         self.messages.poll_next_unpin(cx)
@@ -119,7 +133,7 @@ impl NetStream {
 
     // TODO: Rename `publish_request` or change waits to receive
     // message from server and return success/failure message
-    pub async fn publish(&mut self, name: &str, flag: RecordFlag) -> Result<(), MessageError> {
+    pub async fn publish(&mut self, name: &str, flag: RecordFlag) -> Result<(), Error> {
         trace!(target: "NetStream::publish", "{}: {}", name, flag);
         let result = match self.state {
             Created => {
@@ -135,11 +149,37 @@ impl NetStream {
                 trace!("{:?}", response);
                 Ok(response)
             }
-            _ => unimplemented!(),
+            ref state => Err(Error::Unsupported(format!(
+                "publish on a stream in state {:?}",
+                state
+            ))),
         };
         trace!(target: "NetStream::publish", "publish request sent: {:?}", self);
         result
     }
+
+    /// Sends `@setDataFrame("onMetaData", ...)`, which most servers
+    /// expect from a publisher before audio and video.
+    pub async fn set_metadata(&mut self, metadata: Metadata) -> Result<(), Error> {
+        let data = MessageData::Data {
+            name: "@setDataFrame".to_string(),
+            values: vec![Value::Utf8("onMetaData".to_string()), metadata.into()],
+        };
+        self.cn
+            .send_message(Message::new(Some(self.id), data))
+            .await
+    }
+
+    /// Tells the server how many milliseconds of this stream the player
+    /// buffers, sent before or while playing.
+    pub async fn set_buffer_length(&mut self, buffer_length: u32) -> Result<(), Error> {
+        self.cn
+            .send_signal(Signal::UserControlMessage(Event::SetBufferLength(
+                self.id,
+                buffer_length,
+            )))
+            .await
+    }
 }
 
 // pub struct NetStreamInfo {